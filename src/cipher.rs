@@ -1,74 +1,257 @@
 use core::slice;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
 
 use aead::Buffer;
 use anyhow::Result;
 use rand::RngCore;
 use sha2::Sha256;
 use hkdf::Hkdf;
-use chacha20poly1305::{ChaCha8Poly1305, KeyInit, AeadCore, AeadInPlace, Nonce};
+use hmac::{Hmac, Mac};
+use zeroize::ZeroizeOnDrop;
 
 use crate::constants::TRANSPORT_MTU;
+use crate::padding::Padding;
+use crate::replay::ReplayWindow;
+use crate::suite::{Aead, Suite, MAX_NONCE_SIZE, TAG_SIZE};
+use crate::version;
 
+/// Which end of the tunnel we are. Decides which direction key is used for sending.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+const KEY_SIZE: usize = 32;
+pub const COUNTER_SIZE: usize = 8;
+
+/// Keys derived from the passphrase, one for each direction,
+/// so that a packet reflected back to its sender fails authentication.
+#[derive(Clone, ZeroizeOnDrop)]
+pub struct Keys {
+    client_to_server: [u8; KEY_SIZE],
+    server_to_client: [u8; KEY_SIZE],
+}
+
+impl Keys {
+    pub fn derive(passphrase: &str) -> Keys {
+        Self::derive_from_secret(passphrase.as_bytes(), None)
+    }
+
+    /// Expand the secret into directional keys.
+    /// The secret should either be random, or the output of a password-hardened KDF (see `kdf`).
+    pub fn derive_from_secret(secret: &[u8], salt: Option<&[u8]>) -> Keys {
+        let hkdf = Hkdf::<Sha256>::new(salt, secret);
+        let mut keys = Keys {
+            client_to_server: [0; KEY_SIZE],
+            server_to_client: [0; KEY_SIZE],
+        };
+        hkdf.expand(b"kissvpn client->server", &mut keys.client_to_server).unwrap();
+        hkdf.expand(b"kissvpn server->client", &mut keys.server_to_client).unwrap();
+        keys
+    }
+
+    /// Derive session keys from a handshake.
+    /// Both the shared secret and these keys are mixed in,
+    /// so the result is only known to the two ends that know the passphrase.
+    pub fn derive_session(&self, shared_secret: &[u8], transcript: &[u8]) -> Keys {
+        let mut salt = [0u8; KEY_SIZE * 2];
+        salt[..KEY_SIZE].copy_from_slice(&self.client_to_server);
+        salt[KEY_SIZE..].copy_from_slice(&self.server_to_client);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+        zeroize::Zeroize::zeroize(&mut salt);
+
+        let mut keys = Keys {
+            client_to_server: [0; KEY_SIZE],
+            server_to_client: [0; KEY_SIZE],
+        };
+        let info = |direction: &[u8]| [b"kissvpn session ", direction, b" ", transcript].concat();
+        hkdf.expand(&info(b"client->server"), &mut keys.client_to_server).unwrap();
+        hkdf.expand(&info(b"server->client"), &mut keys.server_to_client).unwrap();
+        keys
+    }
+}
+
+// Wire format of an encrypted packet:
+//
+//   ciphertext (payload + 1 byte version + 8 bytes counter) | tag | nonce | random padding | masked padding length (2 bytes)
+//
+// The padding length is masked with a keyed PRF of the first bytes of ciphertext (like QUIC header protection),
+// and authenticated as associated data, so no plaintext structure is visible and tampering is detected.
+// The ciphertext is never shorter than the sample, because of the counter and tag.
+
+const MASK_SAMPLE_SIZE: usize = 16;
+const PADDING_LEN_SIZE: usize = 2;
+static_assertions::const_assert!(COUNTER_SIZE + TAG_SIZE >= MASK_SAMPLE_SIZE);
+
+const VERSION_SIZE: usize = 1;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn new_mask_mac(key: &[u8]) -> HmacSha256 {
+    let mut mask_key = zeroize::Zeroizing::new([0u8; KEY_SIZE]);
+    Hkdf::<Sha256>::new(None, key).expand(b"kissvpn padding mask", mask_key.as_mut()).unwrap();
+    <HmacSha256 as Mac>::new_from_slice(mask_key.as_ref()).unwrap()
+}
+
+fn padding_length_mask(mac: &HmacSha256, sample: &[u8]) -> u16 {
+    let mut mac = mac.clone();
+    mac.update(sample);
+    let mac = mac.finalize().into_bytes();
+    u16::from_be_bytes([mac[0], mac[1]])
+}
+
+// Clones share the same packet counter and replay window,
+// so that they can be used separately in sending and receiving loop
 #[derive(Clone)]
 pub struct Cipher {
-    chacha20: ChaCha8Poly1305,
+    suite: Suite,
+    version: u8,
+    send_aead: Aead,
+    recv_aead: Aead,
+    send_mask_mac: HmacSha256,
+    recv_mask_mac: HmacSha256,
+    padding: Arc<Padding>,
+    send_counter: Arc<AtomicU64>,
+    replay_window: Arc<Mutex<ReplayWindow>>,
+    replayed_packets: Arc<AtomicU64>,
 }
 
-// TODO: how to get these values from chacha20 crate
-const KEY_SIZE: usize = 32;
-const NONCE_SIZE: usize = 12;
+// The counter starts from current time in nanoseconds,
+// so that a restarted peer continues above anything the receiver has seen.
+fn initial_counter() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
 
 impl Cipher {
-    pub fn new(passphrase: &str) -> Cipher {
-        let hkdf = Hkdf::<Sha256>::new(None, passphrase.as_bytes());
-        let mut key = [0 as u8; KEY_SIZE];
-        hkdf.expand(&[], &mut key).unwrap();
+    pub fn new(keys: &Keys, role: Role, suite: Suite) -> Cipher {
+        let (send_key, recv_key) = match role {
+            Role::Client => (&keys.client_to_server, &keys.server_to_client),
+            Role::Server => (&keys.server_to_client, &keys.client_to_server),
+        };
 
         Cipher {
-            chacha20: ChaCha8Poly1305::new_from_slice(&key).unwrap(),
+            suite,
+            version: version::BASE_VERSION,
+            send_aead: Aead::new(suite, send_key),
+            recv_aead: Aead::new(suite, recv_key),
+            send_mask_mac: new_mask_mac(send_key),
+            recv_mask_mac: new_mask_mac(recv_key),
+            padding: Arc::new(Padding::default()),
+            send_counter: Arc::new(AtomicU64::new(initial_counter())),
+            replay_window: Arc::new(Mutex::new(ReplayWindow::new())),
+            replayed_packets: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /// Use this padding policy for sending. Clones made before are not affected.
+    pub fn with_padding(mut self, padding: Arc<Padding>) -> Cipher {
+        self.padding = padding;
+        self
+    }
+
+    /// Use this protocol version for sending, and only accept it when receiving
+    pub fn with_version(mut self, version: u8) -> Cipher {
+        self.version = version;
+        self
+    }
+
+    pub fn suite(&self) -> Suite {
+        self.suite
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Number of authenticated packets rejected by the replay window so far
+    pub fn replayed_packets(&self) -> u64 {
+        self.replayed_packets.load(atomic::Ordering::Relaxed)
+    }
+
     // Encrypt in-place. The buffer capacity must be large enough.
+    // The version and packet counter are appended to the plaintext, so they're authenticated and hidden.
     pub fn encrypt(&self, buf: &mut impl Buffer) -> Result<()> {
+        buf.extend_from_slice(slice::from_ref(&self.version))?;
+        let counter = self.send_counter.fetch_add(1, atomic::Ordering::Relaxed);
+        buf.extend_from_slice(&counter.to_be_bytes())?;
+
         let mut rng = rand::thread_rng();
-        let nonce = ChaCha8Poly1305::generate_nonce(&mut rng);
+        let mut nonce = [0u8; MAX_NONCE_SIZE];
+        let nonce = &mut nonce[..self.suite.nonce_size()];
+        rng.fill_bytes(nonce);
 
-        self.chacha20.encrypt_in_place(&nonce, &[], buf)?;
-        buf.extend_from_slice(&nonce)?;
+        // obfs. pad random bytes to the end, as many as the padding policy decides.
+        let min_len = buf.len() + TAG_SIZE + nonce.len() + PADDING_LEN_SIZE;
+        assert!(min_len <= TRANSPORT_MTU);
+        let n_random_bytes = self.padding.padding_len(min_len, TRANSPORT_MTU, &mut rng);
+        let n_random_bytes_be = (n_random_bytes as u16).to_be_bytes();
 
-        // obfs. pad random 1 to 255 bytes to the end.
-        // the last byte represents count of bytes added
-        let n_random_bytes = i32::min(255, TRANSPORT_MTU as i32 - buf.len() as i32 - 1);
-        assert!(n_random_bytes >= 0);
+        self.send_aead.encrypt(nonce, &n_random_bytes_be, buf)?;
+        buf.extend_from_slice(nonce)?;
 
-        let mut random_bytes = [0u8; 255];
+        let mut random_bytes = [0u8; TRANSPORT_MTU];
         if n_random_bytes > 0 {
-            rng.fill_bytes(&mut random_bytes[0..n_random_bytes as usize]);
-            buf.extend_from_slice(&random_bytes[0..n_random_bytes as usize])?;
+            rng.fill_bytes(&mut random_bytes[0..n_random_bytes]);
+            buf.extend_from_slice(&random_bytes[0..n_random_bytes])?;
         }
-        let n_random_bytes_u8 = n_random_bytes as u8;
-        buf.extend_from_slice(slice::from_ref(&n_random_bytes_u8))?;
+
+        // the last bytes represent count of bytes added, masked
+        let mask = padding_length_mask(&self.send_mask_mac, &buf.as_ref()[..MASK_SAMPLE_SIZE]);
+        buf.extend_from_slice(&(n_random_bytes as u16 ^ mask).to_be_bytes())?;
 
         Ok(())
     }
 
     pub fn decrypt(&self, buf: &mut impl Buffer) -> Result<()> {
-        if buf.len() < 1 {
+        let counter = self.decrypt_unchecked(buf)?;
+        if !self.replay_window.lock().unwrap().check_and_update(counter) {
+            self.replayed_packets.fetch_add(1, atomic::Ordering::Relaxed);
+            anyhow::bail!("Replayed packet, counter = {}", counter);
+        }
+        Ok(())
+    }
+
+    /// Like decrypt, but without the replay window: return the packet counter for the caller to check
+    /// (e.g. in a window of its own, when the key is shared by senders with separate counters)
+    pub fn decrypt_unchecked(&self, buf: &mut impl Buffer) -> Result<u64> {
+        let nonce_size = self.suite.nonce_size();
+        if buf.len() < VERSION_SIZE + COUNTER_SIZE + TAG_SIZE + nonce_size + PADDING_LEN_SIZE {
             anyhow::bail!("Invalid length {}", buf.len());
         }
-        let n_random_bytes = buf.as_ref()[buf.len() - 1] as usize;
+        let mask = padding_length_mask(&self.recv_mask_mac, &buf.as_ref()[..MASK_SAMPLE_SIZE]);
+        let trailer_pos = buf.len() - PADDING_LEN_SIZE;
+        let n_random_bytes_be = (u16::from_be_bytes(buf.as_ref()[trailer_pos..].try_into()?) ^ mask).to_be_bytes();
+        let n_random_bytes = u16::from_be_bytes(n_random_bytes_be) as usize;
 
-        if buf.len() < NONCE_SIZE + 1 + n_random_bytes {
+        // do not modify the buffer until it's authenticated,
+        // so that the caller can try another cipher on failure
+        if buf.len() < VERSION_SIZE + COUNTER_SIZE + TAG_SIZE + nonce_size + PADDING_LEN_SIZE + n_random_bytes {
             anyhow::bail!("Invalid length {}, n random bytes = {}", buf.len(), n_random_bytes);
         }
-        buf.truncate(buf.len() - 1 - n_random_bytes);
+        let nonce_pos = trailer_pos - n_random_bytes - nonce_size;
+        let tag_pos = nonce_pos - TAG_SIZE;
+        let mut nonce = [0u8; MAX_NONCE_SIZE];
+        nonce[..nonce_size].copy_from_slice(&buf.as_ref()[nonce_pos..(nonce_pos + nonce_size)]);
+        let mut tag = [0u8; TAG_SIZE];
+        tag.copy_from_slice(&buf.as_ref()[tag_pos..nonce_pos]);
 
-        let nonce = Nonce::from_slice(&buf.as_ref()[(buf.len() - NONCE_SIZE) ..]).clone();
-        buf.truncate(buf.len() - NONCE_SIZE);
+        self.recv_aead.decrypt(&nonce[..nonce_size], &n_random_bytes_be, &mut buf.as_mut()[..tag_pos], &tag)?;
+        buf.truncate(tag_pos);
 
-        self.chacha20.decrypt_in_place(&nonce, &[], buf)?;
-        Ok(())
+        let counter_pos = buf.len() - COUNTER_SIZE;
+        let counter = u64::from_be_bytes(buf.as_ref()[counter_pos..].try_into()?);
+        let version = buf.as_ref()[counter_pos - VERSION_SIZE];
+        buf.truncate(counter_pos - VERSION_SIZE);
+
+        // authenticated, so a mismatch means the peer is broken (or skipped the negotiation)
+        if version != self.version {
+            anyhow::bail!("Unexpected protocol version {}, expected {}", version, self.version);
+        }
+        Ok(counter)
     }
 }
 
@@ -78,24 +261,34 @@ mod tests {
     use bytes::BytesMut;
 
     use super::*;
+    use crate::suite::ALL_SUITES;
+
+    fn new_pair(passphrase: &str) -> (Cipher, Cipher) {
+        new_pair_with_suite(passphrase, Suite::default())
+    }
+
+    fn new_pair_with_suite(passphrase: &str, suite: Suite) -> (Cipher, Cipher) {
+        let keys = Keys::derive(passphrase);
+        (Cipher::new(&keys, Role::Client, suite), Cipher::new(&keys, Role::Server, suite))
+    }
 
     #[test]
     fn test_basic_encrypt_decrypt() -> Result<()> {
         let ciphertext = {
-            let cipher = Cipher::new("key0");
+            let (cipher, _) = new_pair("key0");
 
             let mut buf = BytesMut::from("hello world!");
             buf.reserve(100);
             cipher.encrypt(&mut buf)?;
 
-            assert!(buf.len() > 12 + NONCE_SIZE + 16);
-            assert!(buf.len() <= 12 + NONCE_SIZE + 16 + 256);
+            assert!(buf.len() > 12 + VERSION_SIZE + COUNTER_SIZE + 12 + 16);
+            assert!(buf.len() <= 12 + VERSION_SIZE + COUNTER_SIZE + 12 + 16 + 255 + 2);
             buf
         };
 
         {
             let mut buf = ciphertext.clone();
-            let cipher = Cipher::new("key0");
+            let (_, cipher) = new_pair("key0");
 
             cipher.decrypt(&mut buf)?;
             assert_eq!(buf, "hello world!");
@@ -107,26 +300,169 @@ mod tests {
 
         {
             let mut buf = ciphertext.clone();
-            let cipher = Cipher::new("key1");
+            let (_, cipher) = new_pair("key1");
             assert!(cipher.decrypt(&mut buf).is_err());
         }
 
         Ok(())
     }
 
+    #[test]
+    fn test_replay() -> Result<()> {
+        let (sender, receiver) = new_pair("key0");
+
+        let mut packets = Vec::new();
+        for i in 0..3 {
+            let mut buf = BytesMut::from(format!("packet {}", i).as_str());
+            buf.reserve(100);
+            sender.encrypt(&mut buf)?;
+            packets.push(buf);
+        }
+
+        // out of order is fine
+        receiver.decrypt(&mut packets[1].clone())?;
+        receiver.decrypt(&mut packets[0].clone())?;
+        assert_eq!(receiver.replayed_packets(), 0);
+
+        // but not twice
+        assert!(receiver.decrypt(&mut packets[0].clone()).is_err());
+        assert!(receiver.decrypt(&mut packets[1].clone()).is_err());
+        assert_eq!(receiver.replayed_packets(), 2);
+
+        let mut buf = packets[2].clone();
+        receiver.decrypt(&mut buf)?;
+        assert_eq!(buf, "packet 2");
+        assert!(receiver.decrypt(&mut packets[2].clone()).is_err());
+        assert_eq!(receiver.replayed_packets(), 3);
+
+        // a clone shares the replay window
+        assert!(receiver.clone().decrypt(&mut packets[2].clone()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflection() -> Result<()> {
+        let (client, server) = new_pair("key0");
+
+        let mut buf = BytesMut::from("hello world!");
+        buf.reserve(100);
+        client.encrypt(&mut buf)?;
+
+        // reflected back to the client
+        assert!(client.decrypt(&mut buf.clone()).is_err());
+        server.decrypt(&mut buf)?;
+        assert_eq!(buf, "hello world!");
+
+        let mut buf = BytesMut::from("hello world!");
+        buf.reserve(100);
+        server.encrypt(&mut buf)?;
+        assert!(server.decrypt(&mut buf.clone()).is_err());
+        client.decrypt(&mut buf)?;
+        assert_eq!(buf, "hello world!");
+        Ok(())
+    }
+
+    #[test]
+    fn test_padding_length_is_hidden() -> Result<()> {
+        let (client, server) = new_pair("key0");
+        let plaintext_len = 100;
+        let mut n_visible = 0;
+        for _ in 0..256 {
+            let mut buf = BytesMut::zeroed(plaintext_len);
+            client.encrypt(&mut buf)?;
+            let n_random_bytes = buf.len() - plaintext_len - VERSION_SIZE - COUNTER_SIZE - TAG_SIZE - 12 - PADDING_LEN_SIZE;
+            if u16::from_be_bytes([buf[buf.len() - 2], buf[buf.len() - 1]]) as usize == n_random_bytes {
+                n_visible += 1;
+            }
+
+            // tampering with the padding length is detected
+            let mut tampered = buf.clone();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 1;
+            assert!(server.decrypt(&mut tampered).is_err());
+
+            server.decrypt(&mut buf)?;
+            assert_eq!(buf.len(), plaintext_len);
+        }
+        // would be 256 with cleartext padding length
+        assert!(n_visible < 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_padding_policies() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let server = Cipher::new(&keys, Role::Server, Suite::default());
+        let overhead = VERSION_SIZE + COUNTER_SIZE + TAG_SIZE + 12 + PADDING_LEN_SIZE;
+        for (padding, plaintext_len, expected_len) in [
+            (Padding::None, 100, 100 + overhead),
+            (Padding::Full, 0, TRANSPORT_MTU),
+            (Padding::Full, VPN_MTU, TRANSPORT_MTU),
+            (Padding::Buckets(vec![512, 1024]), 100, 512),
+            (Padding::Buckets(vec![512, 1024]), 512 - overhead, 512),
+            (Padding::Buckets(vec![512, 1024]), 600, 1024),
+            (Padding::Buckets(vec![512, 1024]), 1100, TRANSPORT_MTU),
+        ] {
+            let client = Cipher::new(&keys, Role::Client, Suite::default()).with_padding(Arc::new(padding));
+            let mut buf = BytesMut::zeroed(plaintext_len);
+            client.encrypt(&mut buf)?;
+            assert_eq!(buf.len(), expected_len);
+            server.decrypt(&mut buf)?;
+            assert_eq!(buf.len(), plaintext_len);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_mismatch() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let client = Cipher::new(&keys, Role::Client, Suite::default()).with_version(2);
+        let server = Cipher::new(&keys, Role::Server, Suite::default());
+        assert_eq!(server.version(), version::BASE_VERSION);
+
+        let mut buf = BytesMut::from("hello world!");
+        client.encrypt(&mut buf)?;
+        assert!(server.decrypt(&mut buf.clone()).is_err());
+        server.with_version(2).decrypt(&mut buf)?;
+        assert_eq!(buf, "hello world!");
+        Ok(())
+    }
+
+    #[test]
+    fn test_suites() -> Result<()> {
+        for &suite in ALL_SUITES {
+            let (client, server) = new_pair_with_suite("key0", suite);
+            let mut buf = BytesMut::from("hello world!");
+            buf.reserve(100);
+            client.encrypt(&mut buf)?;
+
+            // a different suite with the same keys cannot decrypt it
+            for &other_suite in ALL_SUITES.iter().filter(|&&x| x != suite) {
+                let (_, other_server) = new_pair_with_suite("key0", other_suite);
+                assert!(other_server.decrypt(&mut buf.clone()).is_err());
+            }
+
+            server.decrypt(&mut buf)?;
+            assert_eq!(buf, "hello world!");
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_all_sizes() -> Result<()> {
-        let cipher = Cipher::new("key0");
-        for plaintext_len in 0..=VPN_MTU {
-            let mut plaintext = BytesMut::zeroed(plaintext_len);
-            rand::thread_rng().fill_bytes(&mut plaintext);
+        for &suite in ALL_SUITES {
+            let (client, server) = new_pair_with_suite("key0", suite);
+            for plaintext_len in 0..=VPN_MTU {
+                let mut plaintext = BytesMut::zeroed(plaintext_len);
+                rand::thread_rng().fill_bytes(&mut plaintext);
 
-            let mut buf = plaintext.clone();
-            cipher.encrypt(&mut buf)?;
-            assert!(buf.len() <= TRANSPORT_MTU);
+                let mut buf = plaintext.clone();
+                client.encrypt(&mut buf)?;
+                assert!(buf.len() <= TRANSPORT_MTU);
 
-            cipher.decrypt(&mut buf)?;
-            assert_eq!(plaintext, buf);
+                server.decrypt(&mut buf)?;
+                assert_eq!(plaintext, buf);
+            }
         }
         Ok(())
     }
@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use sha2::{Digest, Sha256};
+
+// Configuration of the tun device of a client, pushed by the server in the handshake response
+// (if the client supports it, see version::FEATURE_TUN_CONFIG).
+//
+// Encoded as a list of `1 byte type | 1 byte length | value`, unknown types are ignored:
+//   1: IPv4 address with prefix length (5 bytes)     2: IPv6 address with prefix length (17 bytes)
+//   3: IPv4 route (5 bytes)                           4: IPv6 route (17 bytes)
+//   5: IPv4 DNS server (4 bytes)                      6: IPv6 DNS server (16 bytes)
+//   7: MTU (2 bytes)
+
+const TYPE_ADDRESS_V4: u8 = 1;
+const TYPE_ADDRESS_V6: u8 = 2;
+const TYPE_ROUTE_V4: u8 = 3;
+const TYPE_ROUTE_V6: u8 = 4;
+const TYPE_DNS_V4: u8 = 5;
+const TYPE_DNS_V6: u8 = 6;
+const TYPE_MTU: u8 = 7;
+
+/// Max encoded size, so that it fits in the handshake response
+pub const MAX_ENCODED_SIZE: usize = 1024;
+
+/// IP network (or address, in an address with prefix length), e.g. 10.9.0.0/24
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<IpNet> {
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max_prefix {
+            anyhow::bail!("Invalid prefix length {} for {}", prefix, addr);
+        }
+        Ok(IpNet { addr, prefix })
+    }
+
+    fn max_prefix(&self) -> u8 {
+        if self.addr.is_ipv4() { 32 } else { 128 }
+    }
+
+    fn to_bits(addr: IpAddr) -> u128 {
+        match addr {
+            IpAddr::V4(x) => u32::from(x) as u128,
+            IpAddr::V6(x) => u128::from(x),
+        }
+    }
+
+    fn with_bits(&self, bits: u128) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+        }
+    }
+
+    fn host_bits(&self) -> u32 {
+        (self.max_prefix() - self.prefix) as u32
+    }
+
+    fn mask(&self) -> u128 {
+        let all = if self.addr.is_ipv4() { u32::MAX as u128 } else { u128::MAX };
+        all & !u128::MAX.checked_shr(self.prefix as u32 + 128 - self.max_prefix() as u32).unwrap_or(0)
+    }
+
+    /// The network address, with host bits cleared
+    pub fn network(&self) -> IpNet {
+        IpNet { addr: self.with_bits(Self::to_bits(self.addr) & self.mask()), prefix: self.prefix }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        addr.is_ipv4() == self.addr.is_ipv4()
+            && Self::to_bits(addr) & self.mask() == Self::to_bits(self.addr) & self.mask()
+    }
+
+    /// Whether the networks have addresses in common, i.e. one contains the other
+    pub fn overlaps(&self, other: &IpNet) -> bool {
+        if self.prefix <= other.prefix {
+            self.contains(other.addr)
+        } else {
+            other.contains(self.addr)
+        }
+    }
+
+    /// Address at the offset in this network, with the same prefix length
+    fn host(&self, offset: u128) -> IpNet {
+        IpNet { addr: self.with_bits((Self::to_bits(self.addr) & self.mask()) | offset), prefix: self.prefix }
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    /// Single address
+    fn from(addr: IpAddr) -> Self {
+        IpNet { addr, prefix: if addr.is_ipv4() { 32 } else { 128 } }
+    }
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    /// `addr/prefix`, or a single address
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        match prefix {
+            Some(prefix) => IpNet::new(addr, prefix),
+            None => Ok(IpNet::from(addr)),
+        }
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TunConfig {
+    /// Addresses of the tun device, with the prefix length of the network
+    pub addresses: Vec<IpNet>,
+    pub routes: Vec<IpNet>,
+    pub dns: Vec<IpAddr>,
+    pub mtu: Option<u16>,
+}
+
+fn put_addr(buf: &mut BytesMut, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(x) => buf.put_slice(&x.octets()),
+        IpAddr::V6(x) => buf.put_slice(&x.octets()),
+    }
+}
+
+fn get_addr(value: &[u8]) -> Result<IpAddr> {
+    match value.len() {
+        4 => Ok(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(value)?))),
+        16 => Ok(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value)?))),
+        _ => anyhow::bail!("Invalid address length {}", value.len()),
+    }
+}
+
+fn get_net(value: &[u8]) -> Result<IpNet> {
+    let (prefix, addr) = value.split_last().ok_or(anyhow::format_err!("Empty network"))?;
+    IpNet::new(get_addr(addr)?, *prefix)
+}
+
+impl TunConfig {
+    pub fn encode(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        let mut put = |type_v4: u8, type_v6: u8, addr: IpAddr, prefix: Option<u8>| {
+            let addr_len = if addr.is_ipv4() { 4 } else { 16 };
+            buf.put_u8(if addr.is_ipv4() { type_v4 } else { type_v6 });
+            buf.put_u8(addr_len + prefix.is_some() as u8);
+            put_addr(&mut buf, addr);
+            if let Some(prefix) = prefix {
+                buf.put_u8(prefix);
+            }
+        };
+        for x in &self.addresses {
+            put(TYPE_ADDRESS_V4, TYPE_ADDRESS_V6, x.addr, Some(x.prefix));
+        }
+        for x in &self.routes {
+            put(TYPE_ROUTE_V4, TYPE_ROUTE_V6, x.addr, Some(x.prefix));
+        }
+        for &x in &self.dns {
+            put(TYPE_DNS_V4, TYPE_DNS_V6, x, None);
+        }
+        if let Some(mtu) = self.mtu {
+            buf.put_u8(TYPE_MTU);
+            buf.put_u8(2);
+            buf.put_u16(mtu);
+        }
+        buf
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<TunConfig> {
+        let mut config = TunConfig::default();
+        while buf.has_remaining() {
+            if buf.remaining() < 2 {
+                anyhow::bail!("Invalid tun config");
+            }
+            let value_type = buf.get_u8();
+            let len = buf.get_u8() as usize;
+            if buf.remaining() < len {
+                anyhow::bail!("Invalid tun config");
+            }
+            let value = &buf[..len];
+            match value_type {
+                TYPE_ADDRESS_V4 | TYPE_ADDRESS_V6 => config.addresses.push(get_net(value)?),
+                TYPE_ROUTE_V4 | TYPE_ROUTE_V6 => config.routes.push(get_net(value)?.network()),
+                TYPE_DNS_V4 | TYPE_DNS_V6 => config.dns.push(get_addr(value)?),
+                TYPE_MTU => config.mtu = Some(u16::from_be_bytes(value.try_into()?)),
+                _ => {},
+            }
+            buf.advance(len);
+        }
+        Ok(config)
+    }
+}
+
+struct Pool {
+    network: IpNet,
+    /// peer name -> offset of its address in the network
+    assigned: HashMap<String, u128>,
+    used: HashSet<u128>,
+}
+
+impl Pool {
+    /// Offset 0 is the network address, 1 is the server, and the last one is reserved (broadcast for IPv4)
+    fn size(&self) -> u128 {
+        1u128.checked_shl(self.network.host_bits()).unwrap_or(u128::MAX)
+    }
+
+    fn assign(&mut self, name: &str) -> Result<IpNet> {
+        if let Some(&offset) = self.assigned.get(name) {
+            return Ok(self.network.host(offset));
+        }
+        let n_clients = self.size() - 3;
+        if self.used.len() as u128 >= n_clients {
+            anyhow::bail!("Address pool {} is exhausted", self.network);
+        }
+        // start from a hash of the name, so that the address is usually the same after restarts
+        let hash = Sha256::digest(name.as_bytes());
+        let mut index = u128::from_be_bytes(hash[..16].try_into().unwrap()) % n_clients;
+        while self.used.contains(&(index + 2)) {
+            index = (index + 1) % n_clients;
+        }
+        let offset = index + 2;
+        self.assigned.insert(name.to_owned(), offset);
+        self.used.insert(offset);
+        Ok(self.network.host(offset))
+    }
+}
+
+/// Server side: address pools, and what to push to each client
+pub struct PushConfig {
+    pools: Mutex<Vec<Pool>>,
+    pub routes: Vec<IpNet>,
+    pub dns: Vec<IpAddr>,
+    pub mtu: u16,
+}
+
+impl PushConfig {
+    /// At most one IPv4 and one IPv6 pool
+    pub fn new(pools: &[IpNet], routes: Vec<IpNet>, dns: Vec<IpAddr>, mtu: u16) -> Result<PushConfig> {
+        for pool in pools {
+            if pool.host_bits() < 2 {
+                anyhow::bail!("Address pool {} is too small", pool);
+            }
+            if pools.iter().filter(|x| x.addr.is_ipv4() == pool.addr.is_ipv4()).count() > 1 {
+                anyhow::bail!("At most one IPv4 and one IPv6 pool");
+            }
+        }
+        let push = PushConfig {
+            pools: Mutex::new(pools.iter().map(|x| Pool {
+                network: x.network(),
+                assigned: HashMap::new(),
+                used: HashSet::new(),
+            }).collect()),
+            routes: routes.iter().map(IpNet::network).collect(),
+            dns,
+            mtu,
+        };
+        if push.config(push.server_addresses()).encode().len() > MAX_ENCODED_SIZE {
+            anyhow::bail!("Too many routes or DNS servers to push");
+        }
+        Ok(push)
+    }
+
+    pub fn has_pool(&self) -> bool {
+        !self.pools.lock().unwrap().is_empty()
+    }
+
+    /// Addresses of the server itself, the first in each pool
+    pub fn server_addresses(&self) -> Vec<IpNet> {
+        self.pools.lock().unwrap().iter().map(|x| x.network.host(1)).collect()
+    }
+
+    fn config(&self, addresses: Vec<IpNet>) -> TunConfig {
+        TunConfig {
+            addresses,
+            routes: self.routes.clone(),
+            dns: self.dns.clone(),
+            mtu: Some(self.mtu),
+        }
+    }
+
+    /// Config of the server's own tun device
+    pub fn server_config(&self) -> TunConfig {
+        TunConfig {
+            addresses: self.server_addresses(),
+            mtu: Some(self.mtu),
+            ..Default::default()
+        }
+    }
+
+    /// Config for a client, with stable addresses assigned to it
+    pub fn client_config(&self, name: &str) -> Result<TunConfig> {
+        let addresses = self.pools.lock().unwrap().iter_mut()
+            .map(|x| x.assign(name))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.config(addresses))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipnet() {
+        assert_eq!(net("10.9.0.7/24").network(), net("10.9.0.0/24"));
+        assert_eq!(net("fd00::1:2/112").network(), net("fd00::1:0/112"));
+        assert_eq!(net("10.9.0.7"), net("10.9.0.7/32"));
+        assert_eq!(net("0.0.0.0/0").network(), net("0.0.0.0/0"));
+        assert!(net("10.9.0.0/24").contains("10.9.0.255".parse().unwrap()));
+        assert!(!net("10.9.0.0/24").contains("10.9.1.0".parse().unwrap()));
+        assert!(!net("10.9.0.0/24").contains("::1".parse().unwrap()));
+        assert!(net("::/0").contains("fd00::1".parse().unwrap()));
+        assert!(net("0.0.0.0/0").contains("1.2.3.4".parse().unwrap()));
+        assert!(net("10.0.0.0/8").overlaps(&net("10.1.0.0/16")));
+        assert!(net("10.1.2.3").overlaps(&net("10.0.0.0/8")));
+        assert!(!net("10.1.0.0/16").overlaps(&net("10.2.0.0/16")));
+        assert!(!net("0.0.0.0/0").overlaps(&net("::/0")));
+        assert!("10.9.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.9.0/24".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_encode_decode() -> Result<()> {
+        let config = TunConfig {
+            addresses: vec![net("10.9.0.2/24"), net("fd00::2/64")],
+            routes: vec![net("192.168.0.0/16"), net("::/0")],
+            dns: vec!["10.9.0.1".parse()?, "fd00::1".parse()?],
+            mtu: Some(1340),
+        };
+        assert_eq!(TunConfig::decode(&config.encode())?, config);
+
+        // unknown types are skipped
+        let mut encoded = BytesMut::from(&[100u8, 3, 1, 2, 3][..]);
+        encoded.extend_from_slice(&config.encode());
+        assert_eq!(TunConfig::decode(&encoded)?, config);
+
+        assert!(TunConfig::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(TunConfig::decode(&[TYPE_ADDRESS_V4, 4, 10, 9, 0, 2]).is_err());
+        assert!(TunConfig::decode(&[TYPE_ADDRESS_V4, 5, 10, 9, 0, 2, 33]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool() -> Result<()> {
+        let push = PushConfig::new(&[net("10.9.0.5/29"), net("fd00::/64")], vec![], vec![], 1340)?;
+        assert_eq!(push.server_addresses(), [net("10.9.0.1/29"), net("fd00::1/64")]);
+
+        let alice = push.client_config("alice")?;
+        assert_eq!(alice.addresses.len(), 2);
+        assert_eq!(push.client_config("alice")?, alice);
+
+        // 5 addresses for clients in a /29
+        let mut v4 = HashSet::new();
+        for name in ["alice", "bob", "carol", "dave", "eve"] {
+            let addr = push.client_config(name)?.addresses[0];
+            assert!(net("10.9.0.0/29").contains(addr.addr));
+            assert!(![net("10.9.0.0/29"), net("10.9.0.1/29"), net("10.9.0.7/29")].contains(&addr));
+            v4.insert(addr);
+        }
+        assert_eq!(v4.len(), 5);
+        assert!(push.client_config("frank").is_err());
+
+        // stable in a new server
+        let push2 = PushConfig::new(&[net("10.9.0.5/29"), net("fd00::/64")], vec![], vec![], 1340)?;
+        assert_eq!(push2.client_config("alice")?, alice);
+
+        assert!(PushConfig::new(&[net("10.9.0.0/31")], vec![], vec![], 1340).is_err());
+        assert!(PushConfig::new(&[net("10.9.0.0/24"), net("10.8.0.0/24")], vec![], vec![], 1340).is_err());
+        Ok(())
+    }
+}
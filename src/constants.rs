@@ -1,17 +1,36 @@
-pub const VPN_MTU: usize = 1362;
+pub const VPN_MTU: usize = 1325;
 
 // VPN_MTU -> TRANSPORT_MTU
-// the encryption requires extra 28 bytes (12 nonce, 16 mac).
-// remaining bytes are for obfs.
+// the encryption requires extra up to 49 bytes (1 version, 8 counter, 12 or 24 nonce depending on suite, 16 mac).
+// remaining bytes are for obfs (at least 2 bytes for the padding length).
 
-pub const TRANSPORT_MTU: usize = 1392;
+pub const TRANSPORT_MTU: usize = 1376;
 
-static_assertions::const_assert!(VPN_MTU + 12 + 16 <= TRANSPORT_MTU);
+static_assertions::const_assert!(VPN_MTU + 1 + 8 + 24 + 16 + 2 <= TRANSPORT_MTU);
 
-// PPPoE MTU = 1492, IPv4 header = 20, UDP header = 8
-// TODO: no support for ipv6 for now
-// So use UDP MTU of 1472
-pub const UDP_MTU: usize = 1464;
+// In TAP mode, VPN_MTU is the max size of the Ethernet frames (without FCS),
+// so the IP MTU of the tap device is 14 bytes smaller.
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+pub const TAP_MTU: usize = VPN_MTU - ETHERNET_HEADER_SIZE;
+
+// PPPoE MTU = 1492, IPv6 header = 40, UDP header = 8
+// So use UDP MTU of 1444 (with IPv4, 20 bytes are left unused)
+pub const UDP_MTU: usize = 1444;
 
 
 pub const BUF_CAPACITY: usize = 1500;
+
+// Blocking reads (tun, transport receive) time out after POLL_INTERVAL,
+// so that the engine notices a shutdown in time.
+
+pub const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+// Sockets of the client's outer transport are marked with FWMARK. When the server pushes a default route,
+// it's installed in ROUTE_TABLE, used by policy rules for unmarked packets only (like wg-quick),
+// so that the outer traffic to the server doesn't loop into the tunnel.
+// The rules have priorities RULE_PRIORITY and the one before, which tell them apart from other tools' rules.
+
+pub const FWMARK: u32 = 0x6b76;
+pub const ROUTE_TABLE: u32 = 0x6b76;
+pub const RULE_PRIORITY: u32 = 0x6b76;
@@ -1,112 +1,532 @@
-use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::{thread, time};
 
 use anyhow::Result;
 use bytes::BytesMut;
-use log::trace;
+use log::{debug, error, info, trace, warn};
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
 
-use crate::constants::BUF_CAPACITY;
+use crate::constants::{BUF_CAPACITY, POLL_INTERVAL};
 use crate::transport::Transport;
-use crate::cipher::Cipher;
+use crate::cipher::Role;
+use crate::peers::Peers;
+use crate::session::{Received, SessionManager, SessionOptions};
 use crate::tun::TunDevice;
 
 
-fn spawn_loop<'scope, F>(scope: &'scope thread::Scope<'scope, '_>, mut f: F)
+/// Errors worth retrying, e.g. the tun write queue full (ENOBUFS) or the network temporarily unreachable.
+/// Other errors stop the engine.
+fn is_transient(e: &anyhow::Error) -> bool {
+    let errno = if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        match e.raw_os_error() {
+            Some(x) => Errno::from_raw(x),
+            None => return matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                                              | std::io::ErrorKind::Interrupted),
+        }
+    } else if let Some(&e) = e.downcast_ref::<Errno>() {
+        e
+    } else {
+        return false;
+    };
+    matches!(errno, Errno::ENOBUFS | Errno::ENOMEM | Errno::EAGAIN | Errno::EINTR | Errno::ETIMEDOUT
+             | Errno::ECONNREFUSED | Errno::ENETUNREACH | Errno::EHOSTUNREACH | Errno::ENETDOWN
+             | Errno::EPERM | Errno::EMSGSIZE)
+}
+
+/// Nothing received within POLL_INTERVAL
+fn is_timeout(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut))
+}
+
+/// State shared by the worker loops: whether to stop, and the fatal error that stopped them
+struct Stop<'a> {
+    requested: &'a AtomicBool,
+    error: Mutex<Option<anyhow::Error>>,
+}
+
+impl Stop<'_> {
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    fn fail(&self, name: &str, e: anyhow::Error) {
+        // errors after the stop are consequences of it (e.g. closed channels)
+        if self.requested.swap(true, Ordering::Relaxed) {
+            debug!("Error in {} while stopping: {}", name, e);
+            return;
+        }
+        error!("Fatal error in {}: {}", name, e);
+        *self.error.lock().unwrap() = Some(e.context(format!("Error in {}", name)));
+    }
+
+    /// Stop on fatal errors, only log transient ones
+    fn check(&self, name: &str, result: Result<()>) {
+        match result {
+            Ok(()) => (),
+            Err(e) if is_transient(&e) => debug!("Transient error in {}: {}", name, e),
+            Err(e) => self.fail(name, e),
+        }
+    }
+}
+
+/// Stop the other loops if a loop panics, the panic is propagated when the scope ends
+struct PanicGuard<'a>(&'a AtomicBool);
+
+impl Drop for PanicGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn spawn_loop<'scope, F>(scope: &'scope thread::Scope<'scope, '_>, stop: &'scope Stop, name: &'static str, mut f: F)
 where F: FnMut() -> anyhow::Result<()> + Send + 'scope {
     scope.spawn(move || {
-        loop {
-            f().unwrap();
+        let _guard = PanicGuard(stop.requested);
+        while !stop.is_requested() {
+            stop.check(name, f());
         }
     });
 }
 
-const CHANNEL_SIZE: usize = 64;
+/// Like Receiver::recv, but returning None after POLL_INTERVAL, to check for stop
+fn recv_or_poll<T>(receiver: &mpsc::Receiver<T>) -> Result<Option<T>> {
+    match receiver.recv_timeout(POLL_INTERVAL) {
+        Ok(x) => Ok(Some(x)),
+        Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How packets are moved between the tun device and the transport
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum EngineKind {
+    /// A thread for each direction and each end, connected by channels;
+    /// a tun reader and a transport sender for each tun queue
+    #[default]
+    Threads,
+    /// A single thread waiting for the tun device (all its queues), the transport and timers with epoll.
+    /// Cheaper on small machines where context switches dominate. Needs Transport::nonblocking_fd.
+    EventLoop,
+}
+
+const ALL_ENGINE_KINDS: &[EngineKind] = &[EngineKind::Threads, EngineKind::EventLoop];
+
+impl EngineKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EngineKind::Threads => "threads",
+            EngineKind::EventLoop => "event-loop",
+        }
+    }
+}
+
+impl std::fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for EngineKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ALL_ENGINE_KINDS.iter().copied().find(|x| x.name() == s)
+            .ok_or(anyhow::format_err!("Unknown engine {}, available: {}", s,
+                                       ALL_ENGINE_KINDS.iter().map(|x| x.name()).collect::<Vec<_>>().join(", ")))
+    }
+}
+
+#[derive(Default)]
+pub struct EngineOptions {
+    pub kind: EngineKind,
+}
+
+/// Max packets moved together, i.e. in a syscall of the transport and in a message of the channels
+const BATCH_SIZE: usize = 64;
+/// In batches
+const CHANNEL_SIZE: usize = 16;
 const KEEPALIVE_INTERVAL: time::Duration = time::Duration::from_secs(60);
+const MAINTAIN_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// Packet handling shared by the engines
 
-pub fn run(tun: TunDevice,
-           transport: impl Transport + 'static,
-           cipher: Cipher) -> Result<()> {
-    let (tun2transport_sender, tun2transport_receiver) = mpsc::sync_channel::<BytesMut>(CHANNEL_SIZE);
-    let (transport2tun_sender, transport2tun_receiver) = mpsc::sync_channel::<BytesMut>(CHANNEL_SIZE);
+/// Encrypt packets read from tun (or an empty keepalive) and send them to the peer(s) they're routed to
+fn send_packets<T: Transport>(sessions: &SessionManager<T::Addr>, transport: &T, bufs: Vec<BytesMut>) -> Result<()> {
+    let mut encrypted = Vec::with_capacity(bufs.len());
+    for mut buf in bufs {
+        if sessions.should_flood(&buf) {
+            encrypted.extend(sessions.encrypt_flood(&buf)?);
+            continue;
+        }
+        match sessions.encrypt(&mut buf)? {
+            Some(addr) => encrypted.push((buf, addr)),
+            None => trace!("No session for the destination, drop packet"),
+        }
+    }
+    if let Err(e) = transport.send_batch(&encrypted) {
+        trace!("Transport send error: {}", e);
+    }
+    Ok(())
+}
+
+/// Receive packets from the transport, none if nothing received (in time)
+fn receive_packets<T: Transport>(transport: &T) -> Result<Vec<(BytesMut, T::Addr)>> {
+    match transport.receive_batch(BATCH_SIZE) {
+        Ok(x) => Ok(x),
+        // only socket errors may be fatal
+        Err(e) if is_transient(&e) || e.downcast_ref::<std::io::Error>().is_none() => {
+            if !is_timeout(&e) {
+                trace!("Transport receive error: {}", e);
+            }
+            Ok(Vec::new())
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Decrypt a received packet and handle control messages, return the data to write to tun
+fn handle_received<T: Transport>(sessions: &SessionManager<T::Addr>, transport: &T, tun: &TunDevice,
+                                 buf: BytesMut, addr: T::Addr) -> Option<BytesMut> {
+    let received = sessions.decrypt(buf, addr.clone());
+    if received.is_ok() {
+        transport.mark_received_valid(&addr);
+    }
+    match received {
+        // empty is for keepalive
+        Ok(Received::Data(buf)) => (!buf.is_empty()).then_some(buf),
+        Ok(Received::Control { reply, config }) => {
+            if let Some(reply) = reply {
+                if let Err(e) = transport.send(reply, &addr) {
+                    trace!("Transport send error: {}", e);
+                }
+            }
+            if let Some(config) = config {
+                if let Err(e) = tun.apply_config(&config) {
+                    warn!("Failed to apply tun config from the server: {}", e);
+                }
+            }
+            None
+        },
+        Err(e) => {
+            trace!("Received invalid packet: {}", e);
+            None
+        },
+    }
+}
+
+/// Start or renew sessions
+fn maintain<T: Transport>(sessions: &SessionManager<T::Addr>, transport: &T) -> Result<()> {
+    if let Some(buf) = sessions.maintain()? {
+        // the same packet to each address, the server only responds to the first it receives
+        for addr in transport.default_addrs() {
+            if let Err(e) = transport.send(buf.clone(), &addr) {
+                trace!("Transport send error: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `peers` have the static keys derived from the passphrase, used for the handshake;
+/// `role` decides which end initiates it, and which direction key is used for sending.
+///
+/// Runs until `shutdown` is set (e.g. by a signal handler) or a fatal error occurs, which is returned.
+/// Either way the transport is shut down and the tun config removed before returning.
+pub fn run<T: Transport + 'static>(tun: TunDevice,
+           transport: T,
+           peers: Peers,
+           role: Role,
+           session_options: SessionOptions,
+           engine_options: EngineOptions,
+           shutdown: &AtomicBool) -> Result<()> {
+    let stop = Stop { requested: shutdown, error: Mutex::new(None) };
+    let sessions = SessionManager::<T::Addr>::new(peers, role, session_options);
+
+    info!("Starting {} engine", engine_options.kind);
+    tun.set_nonblocking()?;
+    match engine_options.kind {
+        EngineKind::Threads => run_threads(&tun, &transport, &sessions, &stop),
+        EngineKind::EventLoop => {
+            let result = run_event_loop(&tun, &transport, &sessions, &stop);
+            stop.check("event loop", result);
+        },
+    }
+
+    info!("Stopping");
+    transport.shutdown();
+    drop(tun);  // removes the addresses and routes
+    match stop.error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn run_threads<T: Transport>(tun: &TunDevice, transport: &T, sessions: &SessionManager<T::Addr>, stop: &Stop) {
+    let (transport2tun_sender, transport2tun_receiver) = mpsc::sync_channel::<Vec<BytesMut>>(CHANNEL_SIZE);
 
     // the timestamp when last tun->transport packet happen
     // used for scheduling keepalive packet
     let last_tun_read = Arc::new(Mutex::new(time::Instant::now() - KEEPALIVE_INTERVAL * 2));
 
     thread::scope(|s| {
-        // read from tun
-        let mut tun_ = &tun;
-        let last_tun_read_ = last_tun_read.clone();
-        let tun2transport_sender_ = tun2transport_sender.clone();
-        spawn_loop(s, move || {
-            let mut buf = BytesMut::zeroed(BUF_CAPACITY);
-            let buf_len = tun_.read(&mut buf)?;
-            buf.truncate(buf_len);
-            *last_tun_read_.lock().unwrap() = time::Instant::now();
-            tun2transport_sender_.send(buf)?;
-            Ok(())
-        });
+        // a pipeline per tun queue: read from tun, send to transport
+        let mut keepalive_sender = None;
+        for queue in 0..tun.num_queues() {
+            let (tun2transport_sender, tun2transport_receiver) = mpsc::sync_channel::<Vec<BytesMut>>(CHANNEL_SIZE);
+            keepalive_sender.get_or_insert_with(|| tun2transport_sender.clone());
 
-        // send to transport
-        let transport_ = &transport;
-        let cipher_ = cipher.clone();
-        spawn_loop(s, move || {
-            let mut buf = tun2transport_receiver.recv()?;
-            cipher_.encrypt(&mut buf)?;
-            if transport_.ready_to_send() {
-                if let Err(e) = transport_.send(buf) {
-                    trace!("Transport send error: {}", e);
+            let last_tun_read_ = last_tun_read.clone();
+            spawn_loop(s, stop, "tun reader", move || {
+                if !tun.wait_readable(queue, POLL_INTERVAL)? {
+                    return Ok(());
                 }
-            }
-            Ok(())
-        });
+                let bufs = tun.read_batch(queue, BATCH_SIZE)?;
+                if !bufs.is_empty() {
+                    *last_tun_read_.lock().unwrap() = time::Instant::now();
+                    tun2transport_sender.send(bufs)?;
+                }
+                Ok(())
+            });
 
-        // receive from transport
-        let transport_ = &transport;
-        let cipher_ = cipher.clone();
-        spawn_loop(s, move || {
-            let mut buf = match transport_.receive() {
-                Ok(buf) => buf,
-                Err(e) => {
-                    trace!("Transport receive error: {}", e);
-                    return Ok(());
-                },
-            };
-            if cipher_.decrypt(&mut buf).is_ok() {
-                transport_.mark_last_received_valid();
-                if !buf.is_empty() {  // empty is for keepalive
-                    transport2tun_sender.send(buf)?;
+            spawn_loop(s, stop, "transport sender", move || {
+                match recv_or_poll(&tun2transport_receiver)? {
+                    Some(bufs) => send_packets(sessions, transport, bufs),
+                    None => Ok(()),
                 }
-            } else {
-                trace!("Received invalid packet (unable to decrypt)");
+            });
+        }
+        let keepalive_sender = keepalive_sender.unwrap();
+
+        // receive from transport
+        spawn_loop(s, stop, "transport receiver", move || {
+            let bufs: Vec<BytesMut> = receive_packets(transport)?.into_iter()
+                .filter_map(|(buf, addr)| handle_received(sessions, transport, tun, buf, addr))
+                .collect();
+            if !bufs.is_empty() {
+                transport2tun_sender.send(bufs)?;
             }
             Ok(())
         });
 
         // write to tun
-        let mut tun_ = &tun;
-        spawn_loop(s, move || {
-            let buf = transport2tun_receiver.recv()?;
-            tun_.write(&buf)?;
-            Ok(())
+        spawn_loop(s, stop, "tun writer", move || {
+            match recv_or_poll(&transport2tun_receiver)? {
+                Some(mut bufs) => tun.write_batch(&mut bufs),
+                None => Ok(()),
+            }
         });
 
-        if transport.needs_keepalive() {
-            spawn_loop(s, move || {
-                let mut last_tun_read_v = *last_tun_read.lock().unwrap();
+        // handshake and keepalive
+        let needs_keepalive = transport.needs_keepalive();
+        spawn_loop(s, stop, "maintainer", move || {
+            maintain(sessions, transport)?;
+
+            if needs_keepalive {
                 let now = time::Instant::now();
-                if now > last_tun_read_v + KEEPALIVE_INTERVAL {
+                let mut last_tun_read_v = last_tun_read.lock().unwrap();
+                if now > *last_tun_read_v + KEEPALIVE_INTERVAL {
                     trace!("Sending keepalive packet");
-                    tun2transport_sender.send(BytesMut::new())?;
-                    last_tun_read_v = now;
-                    *last_tun_read.lock().unwrap() = now;
+                    keepalive_sender.send(vec![BytesMut::with_capacity(BUF_CAPACITY)])?;
+                    *last_tun_read_v = now;
                 }
-                thread::sleep(last_tun_read_v + KEEPALIVE_INTERVAL - now);
-                Ok(())
-            });
-        }
+            }
+            thread::sleep(MAINTAIN_INTERVAL);
+            Ok(())
+        });
     });
+}
+
+// the tun queues are tokens 0..num_queues
+const TRANSPORT_TOKEN: u64 = u64::MAX;
 
+fn run_event_loop<T: Transport>(tun: &TunDevice, transport: &T, sessions: &SessionManager<T::Addr>,
+                                stop: &Stop) -> Result<()> {
+    let Some(transport_fd) = transport.nonblocking_fd()? else {
+        anyhow::bail!("The transport doesn't support the event loop engine");
+    };
+    let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+    for queue in 0..tun.num_queues() {
+        epoll.add(tun.queue_fd(queue), EpollEvent::new(EpollFlags::EPOLLIN, queue as u64))?;
+    }
+    epoll.add(transport_fd, EpollEvent::new(EpollFlags::EPOLLIN, TRANSPORT_TOKEN))?;
+
+    let needs_keepalive = transport.needs_keepalive();
+    let mut last_tun_read = time::Instant::now() - KEEPALIVE_INTERVAL * 2;
+    let mut next_maintain = time::Instant::now();
+    let mut events = vec![EpollEvent::empty(); tun.num_queues() + 1];
+    while !stop.is_requested() {
+        let now = time::Instant::now();
+        if now >= next_maintain {
+            stop.check("maintain", maintain(sessions, transport));
+            if needs_keepalive && now > last_tun_read + KEEPALIVE_INTERVAL {
+                trace!("Sending keepalive packet");
+                stop.check("keepalive", send_packets(sessions, transport, vec![BytesMut::with_capacity(BUF_CAPACITY)]));
+                last_tun_read = now;
+            }
+            next_maintain = now + MAINTAIN_INTERVAL;
+        }
+
+        let timeout = POLL_INTERVAL.min(next_maintain.saturating_duration_since(now));
+        let num_events = match epoll.wait(&mut events, EpollTimeout::try_from(timeout)?) {
+            Err(Errno::EINTR) => continue,
+            x => x?,
+        };
+        // a batch per ready fd, level-triggered epoll reports the rest again
+        for event in &events[..num_events] {
+            match event.data() {
+                TRANSPORT_TOKEN => {
+                    match receive_packets(transport) {
+                        Ok(received) => {
+                            let mut bufs: Vec<BytesMut> = received.into_iter()
+                                .filter_map(|(buf, addr)| handle_received(sessions, transport, tun, buf, addr))
+                                .collect();
+                            stop.check("tun write", tun.write_batch(&mut bufs));
+                        },
+                        Err(e) => stop.check("transport receive", Err(e)),
+                    }
+                },
+                queue => {
+                    match tun.read_batch(queue as usize, BATCH_SIZE) {
+                        Ok(bufs) if bufs.is_empty() => (),
+                        Ok(bufs) => {
+                            last_tun_read = time::Instant::now();
+                            stop.check("transport send", send_packets(sessions, transport, bufs));
+                        },
+                        Err(e) => stop.check("tun read", Err(e)),
+                    }
+                },
+            }
+        }
+    }
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use nix::sched::{unshare, CloneFlags};
+
+    use super::*;
+    use crate::cipher::Keys;
+    use crate::config::{IpNet, TunConfig};
+    use crate::constants::VPN_MTU;
+    use crate::netns::NetNs;
+    use crate::transport::udp::{UdpClientTransport, UdpClientTransportOptions, UdpServerTransport};
+    use crate::tun::TunOptions;
+
+    const DATA_PORT: u16 = 5000;
+
+    /// A new network namespace, which lives while it's open
+    fn new_netns() -> Result<Arc<NetNs>> {
+        thread::spawn(|| {
+            unshare(CloneFlags::CLONE_NEWNET)?;
+            Ok(Arc::new(NetNs::open("/proc/thread-self/ns/net")?))
+        }).join().unwrap()
+    }
+
+    /// A tun device alone in a new namespace, and a UDP socket there on `address`
+    fn tun_in_netns(address: &str) -> Result<(TunDevice, UdpSocket)> {
+        let netns = new_netns()?;
+        let tun = TunDevice::create("kv%d", &TunOptions { netns: Some(netns.clone()), ..Default::default() })?;
+        tun.set_mtu_and_up(VPN_MTU)?;
+        let address: IpNet = address.parse()?;
+        tun.apply_config(&TunConfig { addresses: vec![address], ..Default::default() })?;
+        let sock = netns.run(|| Ok(UdpSocket::bind((address.addr, DATA_PORT))?))?;
+        sock.set_read_timeout(Some(time::Duration::from_millis(100)))?;
+        Ok((tun, sock))
+    }
+
+    /// Send `payload` from the client to the server, which sends it back.
+    /// Datagrams may be lost (e.g. before the session is established), so it's retried for 10s.
+    fn echo(client: &UdpSocket, server: &UdpSocket, payload: &[u8]) -> Result<()> {
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        let server_addr = (server.local_addr()?.ip(), DATA_PORT);
+        let mut buf = [0u8; BUF_CAPACITY];
+        // previous datagrams sent again are skipped
+        let mut round_trip = || -> std::io::Result<()> {
+            client.send_to(payload, server_addr)?;
+            loop {
+                let (n, addr) = server.recv_from(&mut buf)?;
+                if &buf[..n] == payload {
+                    server.send_to(payload, addr)?;
+                    break;
+                }
+            }
+            loop {
+                let n = client.recv(&mut buf)?;
+                if &buf[..n] == payload {
+                    return Ok(());
+                }
+            }
+        };
+        loop {
+            match round_trip() {
+                Ok(()) => return Ok(()),
+                Err(e) if time::Instant::now() < deadline && e.kind() == std::io::ErrorKind::WouldBlock => (),
+                Err(e) => anyhow::bail!("No echo of {:?}: {}", String::from_utf8_lossy(payload), e),
+            }
+        }
+    }
+
+    /// Once the session is established, `count` round trips client -> server -> client
+    fn ping_pong(client: &UdpSocket, server: &UdpSocket, count: usize) -> Result<time::Duration> {
+        echo(client, server, b"hello")?;
+        let start = time::Instant::now();
+        for i in 0..count {
+            echo(client, server, format!("packet {}", i).as_bytes())?;
+        }
+        Ok(start.elapsed())
+    }
+
+    /// Client and server engines of `kind`, connected over UDP on loopback `port`, with tun devices in their own
+    /// namespaces. Return the duration of `count` round trips between them, None without the privileges to set up.
+    fn loopback(kind: EngineKind, port: u16, count: usize) -> Result<time::Duration> {
+        let (server_tun, server_sock) = tun_in_netns("10.9.0.1/24")?;
+        let (client_tun, client_sock) = tun_in_netns("10.9.0.2/24")?;
+        let keys = Keys::derive("key0");
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|s| {
+            let server = s.spawn(|| run(server_tun, UdpServerTransport::create(("127.0.0.1", port))?,
+                                        Peers::single("peer", keys.clone()), Role::Server, SessionOptions::default(),
+                                        EngineOptions { kind }, &shutdown));
+            let client = s.spawn(|| run(client_tun,
+                                        UdpClientTransport::create(("127.0.0.1", port), UdpClientTransportOptions::default())?,
+                                        Peers::single("peer", keys.clone()), Role::Client, SessionOptions::default(),
+                                        EngineOptions { kind }, &shutdown));
+            let result = ping_pong(&client_sock, &server_sock, count);
+            shutdown.store(true, Ordering::Relaxed);
+            server.join().unwrap()?;
+            client.join().unwrap()?;
+            result
+        })
+    }
+
+    /// sudo -E cargo test test_loopback -- --ignored
+    #[test]
+    #[ignore = "needs root, to create network namespaces and tun devices"]
+    fn test_loopback() -> Result<()> {
+        for (kind, port) in [(EngineKind::EventLoop, 9990), (EngineKind::Threads, 9991)] {
+            loopback(kind, port, 100)?;
+        }
+        Ok(())
+    }
+
+    /// cargo test --release bench_loopback -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_loopback() -> Result<()> {
+        const COUNT: usize = 20000;
+        for (kind, port) in [(EngineKind::EventLoop, 9992), (EngineKind::Threads, 9993)] {
+            let elapsed = loopback(kind, port, COUNT)?;
+            println!("{} engine: {:.0} round trips/s", kind, COUNT as f64 / elapsed.as_secs_f64());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+use bytes::{Buf, BufMut, BytesMut};
+use anyhow::Result;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::cipher::{Cipher, Keys, Role};
+use crate::constants::BUF_CAPACITY;
+use crate::suite::Suite;
+use crate::version::{self, Versions};
+
+// Handshake, similar to Noise NNpsk0:
+//   -> e
+//   <- e, ee
+// Both messages are sent as control messages, encrypted with the static keys derived from passphrase
+// (so that only peers knowing the passphrase can complete it, and they look like any other packet).
+// Session keys are derived from the ephemeral-ephemeral DH together with the static keys,
+// so leaking the passphrase later does not expose previous sessions.
+//
+// Control message format (plaintext): 1 byte type + body.
+// Body of handshake messages:
+//   1 byte max version | 1 byte min version | 4 bytes features | 1 byte cipher suite id | 32 bytes public key
+// The client sends what it supports (see version.rs), the server responds with the chosen version and features
+// (as both min and max). Trailing bytes are ignored, so that newer versions can extend the messages.
+// Trailing bytes of the response are a payload for the client, if negotiated by a feature
+// (e.g. the tun config with version::FEATURE_TUN_CONFIG).
+// The init may be followed by the 8 bytes instance id of the client (version::FEATURE_INSTANCE_ID).
+// The client proposes the suite for the session, the server only accepts the one it's configured with.
+// The messages are hashed into the session keys, so nothing above can be tampered with.
+
+pub const MSG_HANDSHAKE_INIT: u8 = 1;
+pub const MSG_HANDSHAKE_RESPONSE: u8 = 2;
+
+/// Suite for the handshake messages themselves, which must be decrypted before knowing the session suite
+pub const HANDSHAKE_SUITE: Suite = Suite::ChaCha20Poly1305;
+
+const PUBLIC_KEY_SIZE: usize = 32;
+const MESSAGE_SIZE: usize = 1 + 2 + 4 + 1 + PUBLIC_KEY_SIZE;
+const INSTANCE_ID_SIZE: usize = 8;
+
+fn encode_message(msg_type: u8, versions: Versions, suite: Suite, public: &PublicKey) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(BUF_CAPACITY);
+    buf.put_u8(msg_type);
+    buf.put_u8(versions.max);
+    buf.put_u8(versions.min);
+    buf.put_u32(versions.features);
+    buf.put_u8(suite.id());
+    buf.put_slice(public.as_bytes());
+    buf
+}
+
+fn decode_message(expected_type: u8, mut msg: &[u8]) -> Result<(Versions, Suite, PublicKey)> {
+    if msg.len() < MESSAGE_SIZE || msg[0] != expected_type {
+        anyhow::bail!("Invalid handshake message");
+    }
+    msg.advance(1);
+    let max = msg.get_u8();
+    let min = msg.get_u8();
+    let features = msg.get_u32();
+    let suite_id = msg.get_u8();
+    let suite = Suite::from_id(suite_id).ok_or(anyhow::format_err!("Unknown cipher suite id {}", suite_id))?;
+    let public: [u8; PUBLIC_KEY_SIZE] = msg[..PUBLIC_KEY_SIZE].try_into()?;
+    if min > max {
+        anyhow::bail!("Invalid version range {}-{}", min, max);
+    }
+    Ok((Versions { min, max, features }, suite, PublicKey::from(public)))
+}
+
+fn derive_session(static_keys: &Keys, suite: Suite, version: u8, secret: EphemeralSecret, peer_public: &PublicKey,
+                  transcript: &[u8], role: Role) -> Result<Cipher> {
+    let shared = secret.diffie_hellman(peer_public);
+    if !shared.was_contributory() {
+        anyhow::bail!("Non-contributory handshake public key");
+    }
+    let session_keys = static_keys.derive_session(shared.as_bytes(), transcript);
+    Ok(Cipher::new(&session_keys, role, suite).with_version(version))
+}
+
+/// Client side of the handshake
+pub struct Initiator {
+    suite: Suite,
+    secret: EphemeralSecret,
+    init: BytesMut,
+}
+
+impl Initiator {
+    pub fn new(suite: Suite) -> Initiator {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let init = encode_message(MSG_HANDSHAKE_INIT, Versions::local(), suite, &PublicKey::from(&secret));
+        Initiator { suite, secret, init }
+    }
+
+    /// Send the id of this client instance, used by servers supporting version::FEATURE_INSTANCE_ID
+    pub fn with_instance(mut self, instance: u64) -> Initiator {
+        self.init.put_u64(instance);
+        self
+    }
+
+    /// Plaintext of the init message. To be encrypted with the static cipher.
+    pub fn init_message(&self) -> BytesMut {
+        self.init.clone()
+    }
+
+    /// Consume the (decrypted) response message, return the session cipher and the payload of the response
+    pub fn finish(self, static_keys: &Keys, response: &[u8]) -> Result<(Cipher, BytesMut)> {
+        let (versions, suite, response_public) = decode_message(MSG_HANDSHAKE_RESPONSE, response)?;
+        if suite != self.suite {
+            anyhow::bail!("Server responded with cipher suite {}, expected {}", suite, self.suite);
+        }
+        // the server must pick one we support
+        let (version, features) = Versions::local().negotiate(&versions)?;
+        if versions != Versions::exact(version, features) {
+            anyhow::bail!("Server responded with invalid protocol version {}-{}, features {:#x}",
+                          versions.min, versions.max, versions.features);
+        }
+        let cipher = derive_session(static_keys, suite, version, self.secret, &response_public,
+                                    &[&self.init, response].concat(), Role::Client)?;
+        Ok((cipher, BytesMut::from(&response[MESSAGE_SIZE..])))
+    }
+}
+
+/// Server side of the handshake, from a (decrypted) init message
+pub struct Responder<'a> {
+    init: &'a [u8],
+    suite: Suite,
+    version: u8,
+    features: u32,
+    init_public: PublicKey,
+    instance: u64,
+}
+
+impl<'a> Responder<'a> {
+    pub fn new(suite: Suite, init: &'a [u8]) -> Result<Responder<'a>> {
+        let (versions, requested_suite, init_public) = decode_message(MSG_HANDSHAKE_INIT, init)?;
+        if requested_suite != suite {
+            anyhow::bail!("Client requested cipher suite {}, but {} is configured", requested_suite, suite);
+        }
+        let (version, features) = Versions::local().negotiate(&versions)?;
+        let instance = match init.get(MESSAGE_SIZE..MESSAGE_SIZE + INSTANCE_ID_SIZE) {
+            Some(x) if features & version::FEATURE_INSTANCE_ID != 0 => u64::from_be_bytes(x.try_into()?),
+            _ => 0,
+        };
+        Ok(Responder { init, suite, version, features, init_public, instance })
+    }
+
+    /// Features negotiated with the client
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    /// Id of the client instance, 0 if the client didn't send one
+    pub fn instance(&self) -> u64 {
+        self.instance
+    }
+
+    /// Return the plaintext of response message, with the payload appended, and the session cipher
+    pub fn respond(self, static_keys: &Keys, payload: &[u8]) -> Result<(BytesMut, Cipher)> {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let mut response = encode_message(MSG_HANDSHAKE_RESPONSE, Versions::exact(self.version, self.features),
+                                          self.suite, &PublicKey::from(&secret));
+        response.extend_from_slice(payload);
+        let cipher = derive_session(static_keys, self.suite, self.version, secret, &self.init_public,
+                                    &[self.init, &response].concat(), Role::Server)?;
+        Ok((response, cipher))
+    }
+}
+
+/// Server side of the handshake without payload. Consume the (decrypted) init message,
+/// return the plaintext of response message and the session cipher.
+pub fn respond(static_keys: &Keys, suite: Suite, init: &[u8]) -> Result<(BytesMut, Cipher)> {
+    Responder::new(suite, init)?.respond(static_keys, &[])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version;
+
+    #[test]
+    fn test_handshake() -> Result<()> {
+        let keys = Keys::derive("key0");
+
+        let initiator = Initiator::new(Suite::default());
+        let (response, server_cipher) = respond(&keys, Suite::default(), &initiator.init_message())?;
+        let (client_cipher, _) = initiator.finish(&keys, &response)?;
+
+        let mut buf = BytesMut::from("hello world!");
+        buf.reserve(100);
+        client_cipher.encrypt(&mut buf)?;
+        server_cipher.decrypt(&mut buf)?;
+        assert_eq!(buf, "hello world!");
+
+        server_cipher.encrypt(&mut buf)?;
+        client_cipher.decrypt(&mut buf)?;
+        assert_eq!(buf, "hello world!");
+
+        // session keys differ from the static keys, and from other sessions
+        let mut buf = BytesMut::from("hello world!");
+        buf.reserve(100);
+        client_cipher.encrypt(&mut buf)?;
+        assert!(Cipher::new(&keys, Role::Server, Suite::default()).decrypt(&mut buf).is_err());
+
+        let initiator = Initiator::new(Suite::default());
+        let (_, other_server_cipher) = respond(&keys, Suite::default(), &initiator.init_message())?;
+        assert!(other_server_cipher.decrypt(&mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_passphrase() -> Result<()> {
+        let initiator = Initiator::new(Suite::default());
+        let (response, server_cipher) = respond(&Keys::derive("key0"), Suite::default(), &initiator.init_message())?;
+        let (client_cipher, _) = initiator.finish(&Keys::derive("key1"), &response)?;
+
+        let mut buf = BytesMut::from("hello world!");
+        buf.reserve(100);
+        client_cipher.encrypt(&mut buf)?;
+        assert!(server_cipher.decrypt(&mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let keys = Keys::derive("key0");
+        let initiator = Initiator::new(Suite::default());
+        let init = initiator.init_message();
+
+        assert!(respond(&keys, Suite::default(), &init[..init.len() - 1]).is_err());
+        let mut bad_type = init.clone();
+        bad_type[0] = MSG_HANDSHAKE_RESPONSE;
+        assert!(respond(&keys, Suite::default(), &bad_type).is_err());
+
+        // all-zero public key is rejected
+        let mut zero = init.clone();
+        zero[8..].fill(0);
+        assert!(respond(&keys, Suite::default(), &zero).is_err());
+
+        assert!(initiator.finish(&keys, &init).is_err());
+    }
+
+    #[test]
+    fn test_versions() -> Result<()> {
+        let keys = Keys::derive("key0");
+
+        // a newer client, with extended message
+        let initiator = Initiator::new(Suite::default());
+        let mut init = initiator.init_message();
+        init[1] = version::MAX_VERSION + 1;
+        init[3..7].copy_from_slice(&u32::MAX.to_be_bytes());
+        init.extend_from_slice(b"future extension");
+        let (response, server_cipher) = respond(&keys, Suite::default(), &init)?;
+        assert_eq!(server_cipher.version(), version::MAX_VERSION);
+        assert_eq!(decode_message(MSG_HANDSHAKE_RESPONSE, &response)?.0,
+                   Versions::exact(version::MAX_VERSION, version::FEATURES));
+        // but the transcript doesn't match the init message the client sent
+        assert!(initiator.finish(&keys, &response).is_ok_and(|(client_cipher, _)| {
+            let mut buf = BytesMut::from("hello world!");
+            client_cipher.encrypt(&mut buf).unwrap();
+            server_cipher.decrypt(&mut buf).is_err()
+        }));
+
+        // no common version
+        let mut init = Initiator::new(Suite::default()).init_message();
+        init[1] = version::MAX_VERSION + 2;
+        init[2] = version::MAX_VERSION + 1;
+        assert!(respond(&keys, Suite::default(), &init).is_err());
+
+        // server picks a version the client doesn't support
+        let initiator = Initiator::new(Suite::default());
+        let (mut response, _) = respond(&keys, Suite::default(), &initiator.init_message())?;
+        response[1] = version::MAX_VERSION + 1;
+        response[2] = version::MAX_VERSION + 1;
+        assert!(initiator.finish(&keys, &response).is_err());
+
+        // the instance id follows the init message
+        let initiator = Initiator::new(Suite::default()).with_instance(42);
+        assert_eq!(Responder::new(Suite::default(), &initiator.init_message())?.instance(), 42);
+        let (response, _) = respond(&keys, Suite::default(), &initiator.init_message())?;
+        assert!(initiator.finish(&keys, &response).is_ok());
+        let mut init = Initiator::new(Suite::default()).with_instance(42).init_message();
+        init[3..7].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(Responder::new(Suite::default(), &init)?.instance(), 0);
+        assert_eq!(Responder::new(Suite::default(), &Initiator::new(Suite::default()).init_message())?.instance(), 0);
+
+        // or features
+        let initiator = Initiator::new(Suite::default());
+        let (mut response, _) = respond(&keys, Suite::default(), &initiator.init_message())?;
+        response[3..7].copy_from_slice(&(!version::FEATURES).to_be_bytes());
+        assert!(initiator.finish(&keys, &response).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_suites() -> Result<()> {
+        let keys = Keys::derive("key0");
+        for &suite in crate::suite::ALL_SUITES {
+            let initiator = Initiator::new(suite);
+            let (response, server_cipher) = respond(&keys, suite, &initiator.init_message())?;
+            let (client_cipher, _) = initiator.finish(&keys, &response)?;
+            assert_eq!(client_cipher.suite(), suite);
+            assert_eq!(server_cipher.suite(), suite);
+
+            let mut buf = BytesMut::from("hello world!");
+            buf.reserve(100);
+            client_cipher.encrypt(&mut buf)?;
+            server_cipher.decrypt(&mut buf)?;
+            assert_eq!(buf, "hello world!");
+        }
+
+        // mismatch
+        let initiator = Initiator::new(Suite::Aes256Gcm);
+        assert!(respond(&keys, Suite::ChaCha20Poly1305, &initiator.init_message()).is_err());
+
+        let mut init = Initiator::new(Suite::Aes256Gcm).init_message();
+        init[7] = 0xff;
+        assert!(respond(&keys, Suite::Aes256Gcm, &init).is_err());
+        Ok(())
+    }
+}
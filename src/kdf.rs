@@ -0,0 +1,242 @@
+use anyhow::Result;
+use log::warn;
+use zeroize::Zeroizing;
+
+use crate::cipher::Keys;
+
+// Key file format. Either:
+// - a single passphrase, which is used with plain HKDF (same as passing it with `--key`); or
+// - lines of `name = value` (empty lines and lines starting with '#' are ignored).
+//   As soon as one line has a name below, all must, so that a typo is an error rather than a passphrase:
+//
+//     kdf = argon2id           # argon2id (default), scrypt or hkdf
+//     salt = my-deployment     # required for argon2id and scrypt, at least 8 bytes
+//     passphrase = ...         # either passphrase,
+//     key = 0123...ef          # or a raw key in hex (at least 32 bytes), e.g. for hkdf
+//
+//     # argon2id costs
+//     m_cost = 65536           # memory in KiB
+//     t_cost = 3               # iterations
+//     p_cost = 1               # parallelism
+//
+//     # scrypt costs
+//     log_n = 15
+//     r = 8
+//     p = 1
+//
+// The memory-hard KDFs make offline brute force of a captured packet expensive;
+// plain HKDF is only fine for random keys.
+
+const MIN_RAW_KEY_SIZE: usize = 32;
+const MASTER_KEY_SIZE: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Kdf {
+    Hkdf,
+    Argon2id { m_cost: u32, t_cost: u32, p_cost: u32 },
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+pub struct KeyConfig {
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    secret: Zeroizing<Vec<u8>>,
+}
+
+const KNOWN_NAMES: &[&str] = &["kdf", "salt", "passphrase", "key", "m_cost", "t_cost", "p_cost", "log_n", "r", "p"];
+
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once('=')?;
+    let name = name.trim();
+    KNOWN_NAMES.contains(&name).then_some((name, value.trim()))
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    s.as_bytes().chunks(2)
+        .map(|pair| match std::str::from_utf8(pair) {
+            Ok(pair) if pair.len() == 2 => Ok(u8::from_str_radix(pair, 16)?),
+            _ => anyhow::bail!("Invalid hex string"),
+        })
+        .collect()
+}
+
+impl KeyConfig {
+    /// Passphrase used with plain HKDF
+    pub fn from_passphrase(passphrase: &str) -> KeyConfig {
+        KeyConfig {
+            kdf: Kdf::Hkdf,
+            salt: Vec::new(),
+            secret: Zeroizing::new(passphrase.as_bytes().to_vec()),
+        }
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<KeyConfig> {
+        Self::parse(&std::fs::read_to_string(path)
+            .map_err(|e| anyhow::format_err!("Cannot read key file {}: {}", path.display(), e))?)
+    }
+
+    /// Parse content of key file. See above for the format.
+    pub fn parse(content: &str) -> Result<KeyConfig> {
+        let lines: Vec<&str> = content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        if !lines.iter().any(|line| parse_line(line).is_some()) {
+            return Ok(Self::from_passphrase(content.trim()));
+        }
+        if let Some(i) = lines.iter().position(|line| parse_line(line).is_none()) {
+            // without the value, which may be secret
+            match lines[i].split_once('=') {
+                Some((name, _)) => anyhow::bail!("Unknown name {:?} in key file", name.trim()),
+                None => anyhow::bail!("Invalid line in key file, expecting name = value"),
+            }
+        }
+
+        let mut kdf_name = "argon2id";
+        let mut salt = Vec::new();
+        let mut secret = None;
+        let mut costs = std::collections::BTreeMap::new();
+        for (name, value) in lines.into_iter().filter_map(parse_line) {
+            match name {
+                "kdf" => kdf_name = value,
+                "salt" => salt = value.as_bytes().to_vec(),
+                "passphrase" => secret = Some(value.as_bytes().to_vec()),
+                "key" => {
+                    let key = decode_hex(value)?;
+                    if key.len() < MIN_RAW_KEY_SIZE {
+                        anyhow::bail!("Raw key should be at least {} bytes", MIN_RAW_KEY_SIZE);
+                    }
+                    secret = Some(key);
+                },
+                _ => {
+                    costs.insert(name, value.parse::<u32>()
+                                 .map_err(|e| anyhow::format_err!("Invalid {}: {}", name, e))?);
+                },
+            }
+        }
+        let secret = Zeroizing::new(secret.ok_or(anyhow::format_err!("No passphrase or key in key file"))?);
+        let cost = |name: &str, default: u32| costs.get(name).copied().unwrap_or(default);
+
+        let kdf = match kdf_name {
+            "hkdf" => Kdf::Hkdf,
+            "argon2id" => Kdf::Argon2id {
+                m_cost: cost("m_cost", 65536),
+                t_cost: cost("t_cost", 3),
+                p_cost: cost("p_cost", 1),
+            },
+            "scrypt" => Kdf::Scrypt {
+                log_n: u8::try_from(cost("log_n", 15))?,
+                r: cost("r", 8),
+                p: cost("p", 1),
+            },
+            _ => anyhow::bail!("Unknown kdf {}", kdf_name),
+        };
+        if kdf != Kdf::Hkdf && salt.len() < argon2::MIN_SALT_LEN {
+            anyhow::bail!("Salt should be at least {} bytes", argon2::MIN_SALT_LEN);
+        }
+        Ok(KeyConfig { kdf, salt, secret })
+    }
+
+    pub fn derive_keys(&self) -> Result<Keys> {
+        let salt = (!self.salt.is_empty()).then_some(self.salt.as_slice());
+        let mut master_key = Zeroizing::new([0u8; MASTER_KEY_SIZE]);
+        match self.kdf {
+            Kdf::Hkdf => {
+                if self.secret.len() < MIN_RAW_KEY_SIZE {
+                    warn!("Using plain HKDF with a short passphrase, consider a memory-hard KDF in key file");
+                }
+                return Ok(Keys::derive_from_secret(&self.secret, salt));
+            },
+            Kdf::Argon2id { m_cost, t_cost, p_cost } => {
+                let params = argon2::Params::new(m_cost, t_cost, p_cost, Some(MASTER_KEY_SIZE))
+                    .map_err(|e| anyhow::format_err!("Invalid argon2 params: {}", e))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(&self.secret, &self.salt, master_key.as_mut())
+                    .map_err(|e| anyhow::format_err!("Argon2 failed: {}", e))?;
+            },
+            Kdf::Scrypt { log_n, r, p } => {
+                let params = scrypt::Params::new(log_n, r, p, MASTER_KEY_SIZE)?;
+                scrypt::scrypt(&self.secret, &self.salt, &params, master_key.as_mut())?;
+            },
+        }
+        Ok(Keys::derive_from_secret(master_key.as_ref(), salt))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::cipher::{Cipher, Role};
+    use crate::suite::Suite;
+
+    fn same_keys(a: &Keys, b: &Keys) -> bool {
+        let mut buf = BytesMut::from("hello world!");
+        buf.reserve(100);
+        Cipher::new(a, Role::Client, Suite::default()).encrypt(&mut buf).unwrap();
+        Cipher::new(b, Role::Server, Suite::default()).decrypt(&mut buf).is_ok()
+    }
+
+    #[test]
+    fn test_passphrase_only() -> Result<()> {
+        let config = KeyConfig::parse("  key0\n")?;
+        assert_eq!(config.kdf, Kdf::Hkdf);
+        assert!(same_keys(&config.derive_keys()?, &Keys::derive("key0")));
+
+        // not a config, treated as passphrase
+        let config = KeyConfig::parse("hello = world")?;
+        assert!(same_keys(&config.derive_keys()?, &Keys::derive("hello = world")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let config = KeyConfig::parse("
+            # comment
+            salt = some salt
+            passphrase = key0
+        ")?;
+        assert_eq!(config.kdf, Kdf::Argon2id { m_cost: 65536, t_cost: 3, p_cost: 1 });
+        assert_eq!(config.salt, b"some salt");
+
+        let config = KeyConfig::parse("kdf = scrypt\nsalt = some salt\npassphrase = key0\nlog_n = 10\n")?;
+        assert_eq!(config.kdf, Kdf::Scrypt { log_n: 10, r: 8, p: 1 });
+
+        let config = KeyConfig::parse(&format!("kdf = hkdf\nkey = {}", "ab".repeat(32)))?;
+        assert_eq!(config.kdf, Kdf::Hkdf);
+        assert_eq!(config.secret.as_slice(), &[0xab; 32]);
+
+        // errors
+        assert!(KeyConfig::parse("kdf = argon2id\npassphrase = key0").is_err());  // no salt
+        assert!(KeyConfig::parse("kdf = foo\nsalt = some salt\npassphrase = key0").is_err());
+        assert!(KeyConfig::parse("kdf = hkdf\nkey = abcd").is_err());  // short key
+        assert!(KeyConfig::parse("kdf = hkdf\nkey = xyz").is_err());
+        assert!(KeyConfig::parse("kdf = hkdf\nsalt = some salt").is_err());  // no secret
+        assert!(KeyConfig::parse("salt = some salt\npassphrase = key0\nt_cost = x").is_err());
+        // typo, not a passphrase
+        assert!(KeyConfig::parse("kdf = argon2id\nsalt = some salt\npasphrase = key0").is_err());
+        assert!(KeyConfig::parse("salt = some salt\nkey0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_derive() -> Result<()> {
+        let argon2 = |salt: &str, passphrase: &str| KeyConfig::parse(&format!(
+            "kdf = argon2id\nsalt = {}\npassphrase = {}\nm_cost = 64\nt_cost = 1", salt, passphrase));
+        let scrypt = |salt: &str, passphrase: &str| KeyConfig::parse(&format!(
+            "kdf = scrypt\nsalt = {}\npassphrase = {}\nlog_n = 4", salt, passphrase));
+
+        for config in [argon2, scrypt] {
+            let keys = config("some salt", "key0")?.derive_keys()?;
+            assert!(same_keys(&keys, &config("some salt", "key0")?.derive_keys()?));
+            assert!(!same_keys(&keys, &config("other salt", "key0")?.derive_keys()?));
+            assert!(!same_keys(&keys, &config("some salt", "key1")?.derive_keys()?));
+            assert!(!same_keys(&keys, &Keys::derive("key0")));
+        }
+        assert!(!same_keys(&argon2("some salt", "key0")?.derive_keys()?,
+                           &scrypt("some salt", "key0")?.derive_keys()?));
+        Ok(())
+    }
+}
@@ -1,6 +1,22 @@
 pub mod transport;
 pub mod cipher;
+pub mod suite;
+pub mod padding;
+pub mod version;
+pub mod replay;
+pub mod kdf;
+pub mod peers;
+pub mod privileges;
+pub mod packet;
+pub mod config;
+pub mod routing;
+pub mod handshake;
+pub mod session;
 pub mod engine;
 pub mod constants;
 pub mod tun;
+pub mod vnet;
+pub mod mss;
+pub mod netlink;
+pub mod netns;
 pub mod faketcp;
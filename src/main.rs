@@ -1,24 +1,94 @@
-use std::io::Read;
+use std::net::IpAddr;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use kissvpn::cipher::Cipher;
-use kissvpn::constants::VPN_MTU;
-use kissvpn::engine;
+use kissvpn::cipher::Role;
+use kissvpn::config::{IpNet, PushConfig};
+use kissvpn::constants::{TAP_MTU, VPN_MTU};
+use kissvpn::engine::{self, EngineKind, EngineOptions};
+use kissvpn::kdf::KeyConfig;
+use kissvpn::netns::{self, NetNs};
+use kissvpn::padding::Padding;
+use kissvpn::peers::Peers;
+use kissvpn::privileges::{self, Capability, PrivilegeOptions};
+use kissvpn::session::SessionOptions;
+use kissvpn::suite::Suite;
 use kissvpn::transport::fakedns::{FakednsClientTransport, FakednsServerTransport};
 use kissvpn::transport::udp::UdpClientTransportOptions;
-use kissvpn::tun::TunDevice;
-use log::info;
+use kissvpn::tun::{self, TunDevice, TunOptions};
+use log::{info, warn};
 use clap::{Parser, Subcommand};
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
 
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(short, long, help="Key string. If key starts with @, then read from the file")]
-    key: String,
+    #[arg(short, long, help="Key string. If key starts with @, then read from the file, \
+                                  which may also configure the KDF (see kdf.rs)")]
+    key: Option<String>,
 
-    #[arg(short, long, help="Run this script to configure interface. Arg: IFACE")]
+    #[arg(short, long, help="Run this script to configure interface (in --netns if any). Arg: IFACE")]
     up_script: Option<String>,
 
+    #[arg(short, long, help="Run this script after the VPN stopped and the interface is removed. Arg: IFACE")]
+    down_script: Option<String>,
+
+    #[arg(long, default_value_t = Suite::default(),
+          help="Cipher suite: chacha20-poly1305, xchacha20-poly1305, aes-256-gcm or chacha8-poly1305. \
+                Must be the same on both ends")]
+    cipher: Suite,
+
+    #[arg(long, default_value_t = Padding::default(),
+          help="Padding policy for sent packets: none, uniform, full, buckets:SIZE,SIZE,... \
+                or distribution:FILE (see padding.rs)")]
+    padding: Padding,
+
+    #[arg(long, default_value_t = EngineKind::default(),
+          help="Engine: threads, or event-loop (single-threaded, cheaper on small machines)")]
+    engine: EngineKind,
+
+    #[arg(long, default_value_t = 1,
+          help="Tun device queues, each read by its own thread with the threads engine (multi-queue if > 1)")]
+    queues: usize,
+
+    #[arg(long, help="TAP mode: carry Ethernet frames instead of IP packets. Must be the same on both ends")]
+    tap: bool,
+
+    #[arg(long, help="Let the tun device hand over and take large TCP packets (TSO/GSO with a vnet header), \
+                      segmented and coalesced by kissvpn. Not in TAP mode")]
+    offload: bool,
+
+    #[arg(long, help="Lower the MSS of inner TCP connections to fit the tunnel MTU, \
+                      for paths where large packets are dropped without notice")]
+    clamp_mss: bool,
+
+    #[arg(long, help="Tun device to use, e.g. a persistent one created with mktun (default: a new tunN or tapN)")]
+    tun: Option<String>,
+
+    #[arg(long, conflicts_with = "tun",
+          help="Use a tun file descriptor inherited from the parent process. Repeat for each queue")]
+    tun_fd: Vec<RawFd>,
+
+    #[arg(long, help="Create and configure the tun device in this network namespace (a name from `ip netns` \
+                      or a path), the outer transport stays in the current one. Needs CAP_SYS_ADMIN, \
+                      also to apply the config pushed by the server, which --user does not keep")]
+    netns: Option<String>,
+
+    #[arg(long, help="Once set up, switch to this user (name or uid). The down script runs as this user too")]
+    user: Option<String>,
+
+    #[arg(long, requires = "user", help="Group to switch to with --user (default: the user's primary group)")]
+    group: Option<String>,
+
+    #[arg(long, requires = "user", value_delimiter = ',',
+          help="Capabilities to keep with --user: net_admin (to apply the config pushed by the server, \
+                mark new sockets and clean up), net_bind_service, net_raw")]
+    keep_caps: Vec<Capability>,
+
     #[command(subcommand)]
     action: Action,
 
@@ -30,12 +100,49 @@ struct Args {
 enum Action {
     Serve {
         bind: String,
+
+        #[arg(long,
+              help="Peers file with a key for each client, instead of --key (see peers.rs)")]
+        peers: Option<PathBuf>,
+
+        #[arg(long, help="Address pool for clients, e.g. 10.9.0.0/24, once for IPv4 and once for IPv6. \
+                          The server takes the first address of each, clients get stable addresses")]
+        pool: Vec<IpNet>,
+
+        #[arg(long, help="Route to push to clients, e.g. networks behind the server. Can be repeated")]
+        push_route: Vec<IpNet>,
+
+        #[arg(long, help="DNS server to push to clients. Can be repeated")]
+        push_dns: Vec<IpAddr>,
     },
     Connect {
         remote: String,
 
         #[arg(long, default_value_t = 10)]
         num_sockets: i32,
+
+        #[arg(long, help="Start a new session after this many packets")]
+        rekey_after_packets: Option<u64>,
+
+        #[arg(long, help="Start a new session after this many bytes")]
+        rekey_after_bytes: Option<u64>,
+
+        #[arg(long, help="Start a new session after this many minutes")]
+        rekey_after_minutes: Option<u64>,
+    },
+    /// Create a persistent tun device, which its owner can then use with --tun without privileges
+    Mktun {
+        name: String,
+
+        #[arg(long, help="User owning the device (name or uid)")]
+        owner: Option<String>,
+
+        #[arg(long, help="Group owning the device (name or gid)")]
+        group: Option<String>,
+    },
+    /// Remove a persistent tun device
+    Rmtun {
+        name: String,
     },
 }
 
@@ -49,6 +156,33 @@ fn run_cmd(cmd: &str, args: &[&str]) -> anyhow::Result<()> {
     Ok(())
 }
 
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_shutdown_signal(_: nix::libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+/// Stop the engine on SIGINT or SIGTERM, which then cleans up
+fn install_signal_handlers() -> anyhow::Result<()> {
+    let action = SigAction::new(SigHandler::Handler(handle_shutdown_signal), SaFlags::empty(), SigSet::empty());
+    for sig in [Signal::SIGINT, Signal::SIGTERM] {
+        unsafe { signal::sigaction(sig, &action)?; }
+    }
+    Ok(())
+}
+
+/// Take ownership of file descriptors inherited from the parent process
+fn inherited_fds(fds: &[RawFd]) -> anyhow::Result<Vec<OwnedFd>> {
+    // otherwise it would be closed twice
+    if let Some((_, fd)) = fds.iter().enumerate().find(|&(i, x)| fds[..i].contains(x)) {
+        anyhow::bail!("Duplicated tun fd {}", fd);
+    }
+    for &fd in fds {
+        fcntl(fd, FcntlArg::F_GETFD).map_err(|e| anyhow::format_err!("Invalid tun fd {}: {}", fd, e))?;
+    }
+    Ok(fds.iter().map(|&fd| unsafe { OwnedFd::from_raw_fd(fd) }).collect())
+}
+
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -57,45 +191,124 @@ fn main() -> anyhow::Result<()> {
         .with_level(args.verbose.log_level_filter())
         .init()?;
 
-    // exit the process if any thread panic
-    let original_panic_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        original_panic_hook(info);
-        std::process::exit(1);
-    }));
+    install_signal_handlers()?;
 
-    let tun_dev = TunDevice::create("tun%d")?;
-    let tun_name = tun_dev.name();
-    info!("Tun device created: {tun_name}");
+    let netns = args.netns.as_deref().map(NetNs::open).transpose()?.map(Arc::new);
+    let tun_options = TunOptions {
+        tap: args.tap,
+        queues: args.queues,
+        offload: args.offload,
+        netns: netns.clone(),
+        clamp_mss: args.clamp_mss,
+    };
+    match &args.action {
+        Action::Mktun { name, owner, group } => {
+            let owner = owner.as_deref().map(privileges::lookup_user).transpose()?.map(|(uid, _)| uid);
+            let group = group.as_deref().map(privileges::lookup_group).transpose()?;
+            let name = tun::create_persistent(name, &tun_options, owner, group)?;
+            info!("Persistent tun device created: {name}");
+            return Ok(());
+        },
+        Action::Rmtun { name } => {
+            tun::delete_persistent(name, &tun_options)?;
+            info!("Persistent tun device removed: {name}");
+            return Ok(());
+        },
+        _ => (),
+    }
+
+    let tun_dev = if args.tun_fd.is_empty() {
+        let default_name = if args.tap { "tap%d" } else { "tun%d" };
+        TunDevice::create(args.tun.as_deref().unwrap_or(default_name), &tun_options)?
+    } else {
+        let fds = inherited_fds(&args.tun_fd)?;
+        TunDevice::from_fds(fds, &tun_options)?
+    };
+    let tun_name = tun_dev.name().to_owned();
+    match &netns {
+        Some(netns) => info!("Tun device created: {tun_name} in network namespace {}", netns.name()),
+        None => info!("Tun device created: {tun_name}"),
+    }
 
-    run_cmd("ip", &["link", "set", tun_name, "mtu", &format!("{}", VPN_MTU), "up"])?;
+    let mtu = if args.tap { TAP_MTU } else { VPN_MTU };
+    tun_dev.set_mtu_and_up(mtu)?;
     if let Some(up_script) = &args.up_script {
-        run_cmd(&up_script, &[tun_name])?;
+        netns::run_in(netns.as_deref(), || run_cmd(up_script, &[&tun_name]))?;
     }
 
-    let key = if args.key.starts_with("@") {
-        let mut f = std::fs::File::open(&args.key[1..])?;
-        let mut result = String::new();
-        f.read_to_string(&mut result)?;
-        result.trim().into()
-    } else {
-        args.key.clone()
+    let peers = match (&args.action, &args.key) {
+        (Action::Serve { peers: Some(_), .. }, Some(_)) => anyhow::bail!("--key and --peers are exclusive"),
+        (Action::Serve { peers: Some(path), .. }, None) => Peers::load(path)?,
+        (_, None) => anyhow::bail!("No key specified"),
+        (action, Some(key)) => {
+            let key_config = match key.strip_prefix('@') {
+                Some(path) => KeyConfig::from_file(Path::new(path))?,
+                None => KeyConfig::from_passphrase(key),
+            };
+            let peer_name = if matches!(action, Action::Serve { .. }) { "client" } else { "server" };
+            Peers::single(peer_name, key_config.derive_keys()?)
+        },
     };
-    let cipher = Cipher::new(&key);
 
-    match args.action {
-        Action::Serve { bind } => {
+    let engine_options = EngineOptions { kind: args.engine };
+    let privilege_options = args.user.map(|user| PrivilegeOptions {
+        user,
+        group: args.group,
+        keep_caps: args.keep_caps,
+    });
+    let result = match args.action {
+        Action::Serve { bind, pool, push_route, push_dns, .. } => {
+            let push = if pool.is_empty() && push_route.is_empty() && push_dns.is_empty() {
+                None
+            } else {
+                let push = PushConfig::new(&pool, push_route, push_dns, mtu as u16)?;
+                tun_dev.apply_config(&push.server_config())?;
+                Some(Arc::new(push))
+            };
             let transport = FakednsServerTransport::create(&bind)?;
-            engine::run(tun_dev, transport, cipher)
+            if let Some(options) = &privilege_options {
+                privileges::drop_privileges(options)?;
+            }
+            let session_options = SessionOptions {
+                suite: args.cipher,
+                padding: Arc::new(args.padding),
+                push,
+                tap: args.tap,
+                ..Default::default()
+            };
+            engine::run(tun_dev, transport, peers, Role::Server, session_options, engine_options, &SHUTDOWN)
         },
-        Action::Connect { remote, num_sockets } => {
+        Action::Connect { remote, num_sockets,
+                          rekey_after_packets, rekey_after_bytes, rekey_after_minutes } => {
+            let defaults = SessionOptions::default();
+            let session_options = SessionOptions {
+                rekey_after_packets: rekey_after_packets.unwrap_or(defaults.rekey_after_packets),
+                rekey_after_bytes: rekey_after_bytes.unwrap_or(defaults.rekey_after_bytes),
+                rekey_after_time: rekey_after_minutes.map_or(
+                    defaults.rekey_after_time, |m| std::time::Duration::from_secs(m * 60)),
+                suite: args.cipher,
+                padding: Arc::new(args.padding),
+                tap: args.tap,
+                ..defaults
+            };
             let transport = FakednsClientTransport::create(
                 &remote,
                 UdpClientTransportOptions {
                     max_send_sockets: num_sockets as usize,
                     ..Default::default()
                 })?;
-            engine::run(tun_dev, transport, cipher)
+            if let Some(options) = &privilege_options {
+                privileges::drop_privileges(options)?;
+            }
+            engine::run(tun_dev, transport, peers, Role::Client, session_options, engine_options, &SHUTDOWN)
         },
+        Action::Mktun { .. } | Action::Rmtun { .. } => unreachable!(),
+    };
+
+    if let Some(down_script) = &args.down_script {
+        if let Err(e) = netns::run_in(netns.as_deref(), || run_cmd(down_script, &[&tun_name])) {
+            warn!("Down script failed: {}", e);
+        }
     }
+    result
 }
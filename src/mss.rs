@@ -0,0 +1,167 @@
+use crate::packet::{self, PROTO_TCP};
+
+// TCP MSS clamping: inner TCP connections negotiate their MSS from the MTUs of their endpoints,
+// which may be larger than the tunnel's. Behind middleboxes blackholing path MTU discovery,
+// full-sized segments are then lost and transfers stall.
+//
+// So the MSS option of SYN and SYN-ACK packets passing through the tun device (in either direction)
+// is lowered to what fits the tunnel MTU, and the TCP checksum is updated incrementally (RFC 1624).
+
+const TCP_HEADER_SIZE: usize = 20;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+/// Largest MSS fitting an IP MTU: without the IP and TCP headers (TCP options are taken from the payload)
+pub fn max_mss(mtu: usize, ipv6: bool) -> u16 {
+    let ip_header_size = if ipv6 { 40 } else { 20 };
+    mtu.saturating_sub(ip_header_size + TCP_HEADER_SIZE).min(u16::MAX as usize) as u16
+}
+
+/// Lower the MSS option of a TCP SYN (IPv4 or IPv6) to `max_mss(mtu)`, return whether it was changed
+pub fn clamp(packet: &mut [u8], mtu: usize) -> bool {
+    let Some((PROTO_TCP, offset)) = packet::transport_header(packet) else {
+        return false;
+    };
+    let max = max_mss(mtu, packet[0] >> 4 == 6);
+    let tcp = &mut packet[offset..];
+    if tcp.len() < TCP_HEADER_SIZE || tcp[13] & TCP_FLAG_SYN == 0 {
+        return false;
+    }
+    let header_len = (tcp[12] >> 4) as usize * 4;
+    if header_len > tcp.len() {
+        return false;
+    }
+
+    let mut i = TCP_HEADER_SIZE;
+    while i < header_len {
+        match tcp[i] {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => i += 1,
+            kind => {
+                let len = tcp.get(i + 1).map_or(0, |&x| x as usize);
+                if len < 2 || i + len > header_len {
+                    return false;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if mss <= max {
+                        return false;
+                    }
+                    tcp[i + 2..i + 4].copy_from_slice(&max.to_be_bytes());
+                    // after an odd number of NOPs, the value straddles two 16-bit words of the checksum
+                    let (old, new) = if i & 1 == 0 { (mss, max) } else { (mss.swap_bytes(), max.swap_bytes()) };
+                    update_checksum(&mut tcp[TCP_CHECKSUM_OFFSET..TCP_CHECKSUM_OFFSET + 2], old, new);
+                    return true;
+                }
+                i += len;
+            },
+        }
+    }
+    false
+}
+
+/// HC' = ~(~HC + ~m + m'), for a 16-bit word changed from `old` to `new`
+fn update_checksum(field: &mut [u8], old: u16, new: u16) {
+    let checksum = u16::from_be_bytes([field[0], field[1]]);
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    field.copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One's complement sum of the TCP pseudo header, header and payload: 0xffff if the checksum is valid
+    fn tcp_sum(packet: &[u8], ip_len: usize) -> u16 {
+        let addrs = if ip_len == 20 { &packet[12..20] } else { &packet[8..40] };
+        let tcp_len = packet.len() - ip_len;
+        let mut acc = PROTO_TCP as u32 + tcp_len as u32;
+        for x in addrs.chunks(2).chain(packet[ip_len..].chunks(2)) {
+            acc += u16::from_be_bytes([x[0], *x.get(1).unwrap_or(&0)]) as u32;
+        }
+        while acc > 0xffff {
+            acc = (acc & 0xffff) + (acc >> 16);
+        }
+        acc as u16
+    }
+
+    fn syn_packet(ipv6: bool, flags: u8, options: &[u8]) -> Vec<u8> {
+        let ip_len = if ipv6 { 40 } else { 20 };
+        let mut packet = vec![0u8; ip_len + 20 + options.len()];
+        if ipv6 {
+            packet[0] = 0x60;
+            packet[6] = PROTO_TCP;
+            packet[23] = 2;
+            packet[39] = 1;
+        } else {
+            packet[0] = 0x45;
+            packet[9] = PROTO_TCP;
+            packet[12..16].copy_from_slice(&[10, 9, 0, 2]);
+            packet[16..20].copy_from_slice(&[10, 9, 0, 1]);
+        }
+        let tcp = &mut packet[ip_len..];
+        tcp[0..4].copy_from_slice(&[0xc0, 0x00, 0x00, 0x16]);
+        tcp[12] = (((20 + options.len()) / 4) << 4) as u8;
+        tcp[13] = flags;
+        tcp[20..].copy_from_slice(options);
+        let checksum = !tcp_sum(&packet, ip_len);
+        packet[ip_len + 16..ip_len + 18].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_max_mss() {
+        assert_eq!(max_mss(1325, false), 1285);
+        assert_eq!(max_mss(1325, true), 1265);
+        assert_eq!(max_mss(10, true), 0);
+    }
+
+    #[test]
+    fn test_clamp() {
+        // MSS 1460, SACK permitted, window scale
+        let options = [2, 4, 0x05, 0xb4, 4, 2, 1, 3, 3, 7];
+        for ipv6 in [false, true] {
+            let ip_len = if ipv6 { 40 } else { 20 };
+            let mut packet = syn_packet(ipv6, TCP_FLAG_SYN, &[&options[..], &[0, 0]].concat());
+            assert!(clamp(&mut packet, 1325));
+            assert_eq!(u16::from_be_bytes([packet[ip_len + 22], packet[ip_len + 23]]), max_mss(1325, ipv6));
+            assert_eq!(tcp_sum(&packet, ip_len), 0xffff);
+            // already small enough
+            assert!(!clamp(&mut packet, 1325));
+        }
+
+        // SYN-ACK, MSS after a NOP: at an odd offset
+        let mut packet = syn_packet(false, TCP_FLAG_SYN | 0x10, &[1, 2, 4, 0x05, 0xb4, 1, 1, 1]);
+        assert!(clamp(&mut packet, 1325));
+        assert_eq!(u16::from_be_bytes([packet[43], packet[44]]), 1285);
+        assert_eq!(tcp_sum(&packet, 20), 0xffff);
+    }
+
+    #[test]
+    fn test_clamp_ignored() {
+        // not a SYN
+        let mut packet = syn_packet(false, 0x10, &[2, 4, 0x05, 0xb4]);
+        assert!(!clamp(&mut packet, 1325));
+        // no MSS option
+        let mut packet = syn_packet(false, TCP_FLAG_SYN, &[1, 1, 4, 2]);
+        assert!(!clamp(&mut packet, 1325));
+        // invalid option length
+        let mut packet = syn_packet(false, TCP_FLAG_SYN, &[3, 0, 2, 4]);
+        assert!(!clamp(&mut packet, 1325));
+        // truncated
+        let mut packet = syn_packet(false, TCP_FLAG_SYN, &[2, 4, 0x05, 0xb4]);
+        assert!(!clamp(&mut packet[..40], 1325));
+        // UDP
+        let mut packet = syn_packet(false, TCP_FLAG_SYN, &[2, 4, 0x05, 0xb4]);
+        packet[9] = 17;
+        assert!(!clamp(&mut packet, 1325));
+    }
+}
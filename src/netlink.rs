@@ -0,0 +1,254 @@
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use nix::libc;
+use nix::sys::socket::{self, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType};
+
+use crate::config::IpNet;
+
+// Minimal rtnetlink client to configure an interface (link, addresses, routes, policy rules) without iproute2.
+//
+// Each request is sent with NLM_F_ACK and waits for its ack, so errors are reported per operation.
+// Messages are in host byte order:
+//   nlmsghdr (16 bytes) | family specific header | attributes (2 bytes len | 2 bytes type | value, 4 byte aligned)
+
+const NLMSG_HDR_SIZE: usize = 16;
+const RECV_BUF_SIZE: usize = 8192;
+
+// from linux/fib_rules.h, not in libc
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 2;
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+
+pub struct Netlink {
+    sock: OwnedFd,
+    seq: u32,
+}
+
+fn family(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() { libc::AF_INET as u8 } else { libc::AF_INET6 as u8 }
+}
+
+fn put_attr(buf: &mut BytesMut, attr_type: u16, value: &[u8]) {
+    buf.put_u16_ne((4 + value.len()) as u16);
+    buf.put_u16_ne(attr_type);
+    buf.put_slice(value);
+    buf.put_bytes(0, (4 - value.len() % 4) % 4);
+}
+
+fn addr_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(x) => x.octets().to_vec(),
+        IpAddr::V6(x) => x.octets().to_vec(),
+    }
+}
+
+/// Message with the netlink header, to be followed by the family specific header and attributes
+fn new_message(msg_type: u16, flags: u16) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(256);
+    buf.put_u32_ne(0);  // length, set when sent
+    buf.put_u16_ne(msg_type);
+    buf.put_u16_ne(flags | libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16);
+    buf.put_u32_ne(0);  // sequence number, set when sent
+    buf.put_u32_ne(0);  // port id, 0 for the kernel
+    buf
+}
+
+fn link_message(index: u32, mtu: Option<u32>, up: Option<bool>) -> BytesMut {
+    let mut buf = new_message(libc::RTM_NEWLINK, 0);
+    // ifinfomsg
+    buf.put_u8(libc::AF_UNSPEC as u8);
+    buf.put_u8(0);
+    buf.put_u16_ne(0);
+    buf.put_i32_ne(index as i32);
+    buf.put_u32_ne(if up == Some(true) { libc::IFF_UP as u32 } else { 0 });
+    buf.put_u32_ne(if up.is_some() { libc::IFF_UP as u32 } else { 0 });
+    if let Some(mtu) = mtu {
+        put_attr(&mut buf, libc::IFLA_MTU, &mtu.to_ne_bytes());
+    }
+    buf
+}
+
+fn address_message(msg_type: u16, flags: u16, index: u32, net: IpNet) -> BytesMut {
+    let mut buf = new_message(msg_type, flags);
+    // ifaddrmsg
+    buf.put_u8(family(net.addr));
+    buf.put_u8(net.prefix);
+    buf.put_u8(0);
+    buf.put_u8(libc::RT_SCOPE_UNIVERSE);
+    buf.put_u32_ne(index);
+    put_attr(&mut buf, libc::IFA_LOCAL, &addr_bytes(net.addr));
+    put_attr(&mut buf, libc::IFA_ADDRESS, &addr_bytes(net.addr));
+    buf
+}
+
+fn route_message(msg_type: u16, flags: u16, index: u32, net: IpNet, table: u32) -> BytesMut {
+    let net = net.network();
+    let mut buf = new_message(msg_type, flags);
+    // rtmsg
+    buf.put_u8(family(net.addr));
+    buf.put_u8(net.prefix);
+    buf.put_u8(0);
+    buf.put_u8(0);
+    buf.put_u8(u8::try_from(table).unwrap_or(libc::RT_TABLE_UNSPEC));
+    buf.put_u8(libc::RTPROT_BOOT);
+    buf.put_u8(libc::RT_SCOPE_LINK);
+    buf.put_u8(libc::RTN_UNICAST);
+    buf.put_u32_ne(0);
+    put_attr(&mut buf, libc::RTA_DST, &addr_bytes(net.addr));
+    put_attr(&mut buf, libc::RTA_OIF, &index.to_ne_bytes());
+    put_attr(&mut buf, libc::RTA_TABLE, &table.to_ne_bytes());
+    buf
+}
+
+/// Policy routing rule: lookup `table`, for packets without `fwmark` if given
+/// (else with routes of prefix length 0 suppressed, i.e. ignoring default routes)
+fn rule_message(msg_type: u16, flags: u16, ipv4: bool, priority: u32, table: u32, fwmark: Option<u32>) -> BytesMut {
+    let mut buf = new_message(msg_type, flags);
+    // fib_rule_hdr
+    buf.put_u8(if ipv4 { libc::AF_INET as u8 } else { libc::AF_INET6 as u8 });
+    buf.put_bytes(0, 3);  // dst_len, src_len, tos
+    buf.put_u8(libc::RT_TABLE_UNSPEC);
+    buf.put_bytes(0, 2);
+    buf.put_u8(FR_ACT_TO_TBL);
+    buf.put_u32_ne(if fwmark.is_some() { FIB_RULE_INVERT } else { 0 });
+    put_attr(&mut buf, FRA_PRIORITY, &priority.to_ne_bytes());
+    put_attr(&mut buf, FRA_TABLE, &table.to_ne_bytes());
+    match fwmark {
+        Some(fwmark) => put_attr(&mut buf, FRA_FWMARK, &fwmark.to_ne_bytes()),
+        None => put_attr(&mut buf, FRA_SUPPRESS_PREFIXLEN, &0u32.to_ne_bytes()),
+    }
+    buf
+}
+
+impl Netlink {
+    pub fn new() -> Result<Netlink> {
+        let sock = socket::socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC,
+                                  SockProtocol::NetlinkRoute)?;
+        Ok(Netlink { sock, seq: 0 })
+    }
+
+    /// Set the MTU and/or the up state of a link
+    pub fn set_link(&mut self, index: u32, mtu: Option<u32>, up: Option<bool>) -> Result<()> {
+        self.request(link_message(index, mtu, up))
+    }
+
+    /// Add an address (with the prefix length of its network), or replace it if already there
+    pub fn add_address(&mut self, index: u32, net: IpNet) -> Result<()> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_REPLACE;
+        self.request(address_message(libc::RTM_NEWADDR, flags as u16, index, net))
+    }
+
+    pub fn del_address(&mut self, index: u32, net: IpNet) -> Result<()> {
+        self.request(address_message(libc::RTM_DELADDR, 0, index, net))
+    }
+
+    /// Route a network to the link, replacing any existing route of the network in the table
+    pub fn add_route(&mut self, index: u32, net: IpNet, table: u32) -> Result<()> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_REPLACE;
+        self.request(route_message(libc::RTM_NEWROUTE, flags as u16, index, net, table))
+    }
+
+    pub fn del_route(&mut self, index: u32, net: IpNet, table: u32) -> Result<()> {
+        self.request(route_message(libc::RTM_DELROUTE, 0, index, net, table))
+    }
+
+    /// Send packets without `fwmark` to `table`, like wg-quick:
+    ///     ip rule add not fwmark FWMARK table TABLE
+    ///     ip rule add table main suppress_prefixlength 0
+    /// so that a default route in `table` takes precedence over the one in the main table,
+    /// except for marked packets (i.e. of the outer transport), and more specific routes in main still apply.
+    /// The rules get `priority` and the one before (for the suppress rule, looked up first), which identify them:
+    /// the same rules left by a previous run (e.g. killed before cleaning up) are removed first,
+    /// and rules of other tools (e.g. wg-quick) are left alone.
+    pub fn add_default_route_rules(&mut self, ipv4: bool, priority: u32, table: u32, fwmark: u32) -> Result<()> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let main = libc::RT_TABLE_MAIN as u32;
+        self.request_allowing(rule_message(libc::RTM_DELRULE, 0, ipv4, priority, table, Some(fwmark)), libc::ENOENT)?;
+        self.request_allowing(rule_message(libc::RTM_DELRULE, 0, ipv4, priority - 1, main, None), libc::ENOENT)?;
+        self.request(rule_message(libc::RTM_NEWRULE, flags as u16, ipv4, priority, table, Some(fwmark)))?;
+        self.request(rule_message(libc::RTM_NEWRULE, flags as u16, ipv4, priority - 1, main, None))
+    }
+
+    pub fn del_default_route_rules(&mut self, ipv4: bool, priority: u32, table: u32, fwmark: u32) -> Result<()> {
+        self.request(rule_message(libc::RTM_DELRULE, 0, ipv4, priority - 1, libc::RT_TABLE_MAIN as u32, None))?;
+        self.request(rule_message(libc::RTM_DELRULE, 0, ipv4, priority, table, Some(fwmark)))
+    }
+
+    /// Like `request`, but return false instead of an error for `errno`
+    fn request_allowing(&mut self, msg: BytesMut, errno: i32) -> Result<bool> {
+        match self.request(msg) {
+            Ok(()) => Ok(true),
+            Err(e) if e.downcast_ref::<std::io::Error>().and_then(|x| x.raw_os_error()) == Some(errno) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn request(&mut self, mut msg: BytesMut) -> Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let len = msg.len() as u32;
+        msg[0..4].copy_from_slice(&len.to_ne_bytes());
+        msg[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+        socket::sendto(self.sock.as_raw_fd(), &msg, &NetlinkAddr::new(0, 0), MsgFlags::empty())?;
+
+        let mut buf = vec![0u8; RECV_BUF_SIZE];
+        loop {
+            let n = socket::recv(self.sock.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+            let mut msgs = &buf[..n];
+            while msgs.len() >= NLMSG_HDR_SIZE {
+                let mut header = &msgs[..NLMSG_HDR_SIZE];
+                let msg_len = header.get_u32_ne() as usize;
+                let msg_type = header.get_u16_ne();
+                header.advance(2);
+                let seq = header.get_u32_ne();
+                if msg_len < NLMSG_HDR_SIZE || msg_len > msgs.len() {
+                    anyhow::bail!("Invalid netlink message");
+                }
+                if seq == self.seq && msg_type == libc::NLMSG_ERROR as u16 {
+                    let mut body = &msgs[NLMSG_HDR_SIZE..msg_len];
+                    if body.len() < 4 {
+                        anyhow::bail!("Invalid netlink ack");
+                    }
+                    return match body.get_i32_ne() {
+                        0 => Ok(()),
+                        e => Err(std::io::Error::from_raw_os_error(-e).into()),
+                    };
+                }
+                msgs = &msgs[usize::min(msgs.len(), (msg_len + 3) & !3)..];
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let msg = address_message(libc::RTM_NEWADDR, 0, 7, "10.9.0.2/24".parse().unwrap());
+        assert_eq!(msg.len(), NLMSG_HDR_SIZE + 8 + 8 + 8);
+        assert_eq!(&msg[NLMSG_HDR_SIZE..NLMSG_HDR_SIZE + 2], &[libc::AF_INET as u8, 24]);
+        assert_eq!(&msg[NLMSG_HDR_SIZE + 8 + 4..NLMSG_HDR_SIZE + 8 + 8], &[10, 9, 0, 2]);
+
+        // host bits of the route are cleared, attributes are aligned
+        let msg = route_message(libc::RTM_NEWROUTE, 0, 7, "fd00::1/64".parse().unwrap(), 1000);
+        assert_eq!(msg.len(), NLMSG_HDR_SIZE + 12 + 20 + 8 + 8);
+        assert_eq!(msg[NLMSG_HDR_SIZE + 4], libc::RT_TABLE_UNSPEC);
+        assert_eq!(&msg[NLMSG_HDR_SIZE + 12 + 4..NLMSG_HDR_SIZE + 12 + 20],
+                   &"fd00::".parse::<std::net::Ipv6Addr>().unwrap().octets());
+
+        let msg = link_message(7, Some(1340), Some(true));
+        assert_eq!(msg.len(), NLMSG_HDR_SIZE + 16 + 8);
+
+        let msg = rule_message(libc::RTM_NEWRULE, 0, false, 999, 1000, Some(1));
+        assert_eq!(msg.len(), NLMSG_HDR_SIZE + 12 + 8 + 8 + 8);
+        assert_eq!(msg[NLMSG_HDR_SIZE], libc::AF_INET6 as u8);
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::error;
+use nix::sched::{setns, CloneFlags};
+
+// Network namespaces: the tun device can live in another namespace than the outer transport sockets,
+// so that only the processes inside go through the tunnel, without policy routing (the "wireguard netns trick").
+//
+// A socket stays in the namespace it was created in, so switching is only needed to create the device,
+// to open the netlink sockets configuring it, and for /proc/sys/net. setns(2) only switches the calling thread.
+// It needs CAP_SYS_ADMIN.
+
+/// Where `ip netns add` creates the namespaces
+const NETNS_RUN_DIR: &str = "/run/netns";
+
+pub struct NetNs {
+    name: String,
+    file: File,
+}
+
+/// A name created with `ip netns add`, or a path like /proc/PID/ns/net
+fn netns_path(name: &str) -> PathBuf {
+    if name.contains('/') {
+        PathBuf::from(name)
+    } else {
+        Path::new(NETNS_RUN_DIR).join(name)
+    }
+}
+
+impl NetNs {
+    pub fn open(name: &str) -> Result<NetNs> {
+        let path = netns_path(name);
+        let file = File::open(&path)
+            .map_err(|e| anyhow::format_err!("Cannot open network namespace {}: {}", path.display(), e))?;
+        Ok(NetNs { name: name.to_owned(), file })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run `f` with the calling thread in the namespace, then switch it back
+    pub fn run<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let current = File::open("/proc/thread-self/ns/net")?;
+        setns(self.file.as_fd(), CloneFlags::CLONE_NEWNET)
+            .map_err(|e| anyhow::format_err!("Cannot enter network namespace {}: {}", self.name, e))?;
+        let result = f();
+        // otherwise the sockets later created by this thread (e.g. of the transport) would be in the namespace.
+        // Callers may carry on after errors (e.g. enable_ipv6), so abort rather than return one.
+        if let Err(e) = setns(current.as_fd(), CloneFlags::CLONE_NEWNET) {
+            error!("Cannot return from network namespace {} to the original one: {}, aborting", self.name, e);
+            std::process::abort();
+        }
+        result
+    }
+}
+
+/// Run `f` in `netns` if any, in the current namespace otherwise
+pub fn run_in<T>(netns: Option<&NetNs>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    match netns {
+        Some(netns) => netns.run(f),
+        None => f(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netns_path() {
+        assert_eq!(netns_path("vpn"), PathBuf::from("/run/netns/vpn"));
+        assert_eq!(netns_path("/proc/1/ns/net"), PathBuf::from("/proc/1/ns/net"));
+    }
+
+    #[test]
+    fn test_run_in_none() {
+        assert_eq!(run_in(None, || Ok(42)).unwrap(), 42);
+        assert!(NetNs::open("no-such-netns-kissvpn").is_err());
+    }
+}
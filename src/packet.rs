@@ -0,0 +1,224 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::constants::ETHERNET_HEADER_SIZE;
+
+// Minimal parsing of inner IP packets (as read from / written to the tun device),
+// or Ethernet frames in TAP mode
+
+fn ip_version(packet: &[u8]) -> Option<u8> {
+    packet.first().map(|x| x >> 4)
+}
+
+fn addr_at(packet: &[u8], v4_offset: usize, v6_offset: usize) -> Option<IpAddr> {
+    match ip_version(packet)? {
+        4 => {
+            let bytes: [u8; 4] = packet.get(v4_offset..v4_offset + 4)?.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(bytes)))
+        },
+        6 => {
+            let bytes: [u8; 16] = packet.get(v6_offset..v6_offset + 16)?.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(bytes)))
+        },
+        _ => None,
+    }
+}
+
+/// Source address of an IPv4 or IPv6 packet
+pub fn source_addr(packet: &[u8]) -> Option<IpAddr> {
+    addr_at(packet, 12, 8)
+}
+
+/// Destination address of an IPv4 or IPv6 packet
+pub fn destination_addr(packet: &[u8]) -> Option<IpAddr> {
+    addr_at(packet, 16, 24)
+}
+
+// IPv6 extension headers: hop-by-hop, routing, destination options, fragment, authentication
+const IPV6_EXT_HOP_BY_HOP: u8 = 0;
+const IPV6_EXT_ROUTING: u8 = 43;
+const IPV6_EXT_FRAGMENT: u8 = 44;
+const IPV6_EXT_AH: u8 = 51;
+const IPV6_EXT_DEST_OPTS: u8 = 60;
+
+/// Protocol (e.g. 6 for TCP) and offset of the transport header of an IPv4 or IPv6 packet,
+/// skipping IPv4 options and IPv6 extension headers.
+/// None if truncated or not the first fragment (no transport header then).
+pub fn transport_header(packet: &[u8]) -> Option<(u8, usize)> {
+    match ip_version(packet)? {
+        4 => {
+            let header_len = (packet[0] & 0x0f) as usize * 4;
+            let fragment_offset = u16::from_be_bytes(packet.get(6..8)?.try_into().ok()?) & 0x1fff;
+            if header_len < 20 || header_len > packet.len() || fragment_offset != 0 {
+                return None;
+            }
+            Some((packet[9], header_len))
+        },
+        6 => {
+            let mut next_header = *packet.get(6)?;
+            let mut offset = 40;
+            loop {
+                let ext = packet.get(offset..offset + 8)?;
+                offset += match next_header {
+                    IPV6_EXT_HOP_BY_HOP | IPV6_EXT_ROUTING | IPV6_EXT_DEST_OPTS => (ext[1] as usize + 1) * 8,
+                    IPV6_EXT_AH => (ext[1] as usize + 2) * 4,
+                    IPV6_EXT_FRAGMENT => {
+                        if u16::from_be_bytes([ext[2], ext[3]]) >> 3 != 0 {
+                            return None;
+                        }
+                        8
+                    },
+                    _ => break,
+                };
+                next_header = ext[0];
+            }
+            (offset <= packet.len()).then_some((next_header, offset))
+        },
+        _ => None,
+    }
+}
+
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+/// Hash of the flow of an IPv4 or IPv6 packet: addresses, protocol and TCP/UDP ports.
+/// The same for both directions of a flow. None if not an IP packet.
+pub fn flow_hash(packet: &[u8]) -> Option<u64> {
+    let (src, dst) = (source_addr(packet)?, destination_addr(packet)?);
+    let (proto, ports) = match transport_header(packet) {
+        Some((proto @ (PROTO_TCP | PROTO_UDP), offset)) => match packet.get(offset..offset + 4) {
+            Some(x) => (proto, [u16::from_be_bytes([x[0], x[1]]), u16::from_be_bytes([x[2], x[3]])]),
+            None => (proto, [0, 0]),
+        },
+        Some((proto, _)) => (proto, [0, 0]),
+        // fragments: only the addresses
+        None => (0, [0, 0]),
+    };
+    let ends = if (src, ports[0]) <= (dst, ports[1]) {
+        [(src, ports[0]), (dst, ports[1])]
+    } else {
+        [(dst, ports[1]), (src, ports[0])]
+    };
+    let mut hasher = DefaultHasher::new();
+    (ends, proto).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+pub type MacAddr = [u8; 6];
+
+/// Destination address of an Ethernet frame
+pub fn eth_destination(frame: &[u8]) -> Option<MacAddr> {
+    frame.get(0..6)?.try_into().ok()
+}
+
+/// Source address of an Ethernet frame
+pub fn eth_source(frame: &[u8]) -> Option<MacAddr> {
+    frame.get(6..12)?.try_into().ok()
+}
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// IP packet carried by an Ethernet frame (without VLAN tag)
+pub fn eth_payload_ip(frame: &[u8]) -> Option<&[u8]> {
+    let ethertype = u16::from_be_bytes(frame.get(12..ETHERNET_HEADER_SIZE)?.try_into().ok()?);
+    matches!(ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6).then(|| &frame[ETHERNET_HEADER_SIZE..])
+}
+
+/// Broadcast or multicast
+pub fn is_group_mac(mac: &MacAddr) -> bool {
+    mac[0] & 1 != 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addrs() {
+        let mut v4 = [0u8; 20];
+        v4[0] = 0x45;
+        v4[12..16].copy_from_slice(&[10, 9, 0, 2]);
+        v4[16..20].copy_from_slice(&[10, 9, 0, 1]);
+        assert_eq!(source_addr(&v4), Some("10.9.0.2".parse().unwrap()));
+        assert_eq!(destination_addr(&v4), Some("10.9.0.1".parse().unwrap()));
+        assert_eq!(destination_addr(&v4[..19]), None);
+
+        let mut v6 = [0u8; 40];
+        v6[0] = 0x60;
+        v6[8..24].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        v6[24..40].copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        assert_eq!(source_addr(&v6), Some("fd00::2".parse().unwrap()));
+        assert_eq!(destination_addr(&v6), Some("fd00::1".parse().unwrap()));
+
+        assert_eq!(source_addr(&[]), None);
+        assert_eq!(source_addr(&[0x50; 40]), None);
+    }
+
+    #[test]
+    fn test_transport_header() {
+        let mut v4 = [0u8; 28];
+        v4[0] = 0x46;
+        v4[9] = 6;
+        assert_eq!(transport_header(&v4), Some((6, 24)));
+        v4[7] = 1;  // not the first fragment
+        assert_eq!(transport_header(&v4), None);
+        assert_eq!(transport_header(&v4[..20]), None);
+
+        // hop-by-hop, fragment (first), destination options of 16 bytes, then TCP
+        let mut v6 = [0u8; 80];
+        v6[0] = 0x60;
+        v6[6] = 0;
+        v6[40] = 44;
+        v6[48] = 60;
+        v6[56] = 6;
+        v6[57] = 1;
+        assert_eq!(transport_header(&v6), Some((6, 72)));
+        assert_eq!(transport_header(&v6[..71]), None);
+        v6[51] = 0x08;  // fragment offset 1
+        assert_eq!(transport_header(&v6), None);
+
+        v6[6] = 17;
+        assert_eq!(transport_header(&v6), Some((17, 40)));
+    }
+
+    #[test]
+    fn test_flow_hash() {
+        let mut v4 = [0u8; 24];
+        v4[0] = 0x45;
+        v4[9] = 6;
+        v4[12..16].copy_from_slice(&[10, 9, 0, 2]);
+        v4[16..20].copy_from_slice(&[10, 9, 0, 1]);
+        v4[20..24].copy_from_slice(&[0xc0, 0x00, 0x00, 0x16]);
+        let hash = flow_hash(&v4).unwrap();
+
+        let mut reply = v4;
+        reply[12..16].copy_from_slice(&[10, 9, 0, 1]);
+        reply[16..20].copy_from_slice(&[10, 9, 0, 2]);
+        reply[20..24].copy_from_slice(&[0x00, 0x16, 0xc0, 0x00]);
+        assert_eq!(flow_hash(&reply), Some(hash));
+
+        let mut other = v4;
+        other[21] = 1;  // another source port
+        assert_ne!(flow_hash(&other), Some(hash));
+        assert_eq!(flow_hash(&[0x50; 40]), None);
+
+        let mut frame = vec![0u8; 14];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&v4);
+        assert_eq!(eth_payload_ip(&frame), Some(&v4[..]));
+        frame[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(eth_payload_ip(&frame), None);
+    }
+
+    #[test]
+    fn test_eth() {
+        let frame = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0, 0, 0, 0, 1, 0x08, 0x06];
+        assert_eq!(eth_destination(&frame), Some([0xff; 6]));
+        assert_eq!(eth_source(&frame), Some([0x02, 0, 0, 0, 0, 1]));
+        assert!(is_group_mac(&eth_destination(&frame).unwrap()));
+        assert!(!is_group_mac(&eth_source(&frame).unwrap()));
+        assert_eq!(eth_source(&frame[..11]), None);
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::Result;
+use rand::Rng;
+use rand::distributions::{Distribution, WeightedIndex};
+
+// Length shaping of encrypted packets, by adding random bytes before the padding length trailer.
+// Only the sender's policy matters (the receiver reads the padding length from the packet),
+// so each end can use a different one.
+//
+// Lengths below are of the whole encrypted packet (what's visible on the link, before the transport framing).
+// Command line syntax:
+//
+//     none                     no padding
+//     uniform                  random 0 to 255 bytes (default)
+//     buckets:256,512,1024     pad to the smallest bucket that fits, or to full MTU if none
+//     full                     always pad to TRANSPORT_MTU
+//     distribution:FILE        sample lengths from a distribution, see below
+//
+// The distribution file has one `length [weight]` per line (weight defaults to 1, so a list of observed
+// packet lengths works as is). Empty lines and lines starting with '#' are ignored.
+// Each packet is padded to a length sampled from the entries not shorter than it, or not padded if none.
+
+/// Max random bytes added by the uniform policy
+const UNIFORM_MAX: usize = 255;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    None,
+    #[default]
+    Uniform,
+    Buckets(Vec<usize>),
+    Full,
+    /// (length, weight) pairs, sorted by length
+    Distribution(Vec<(usize, u32)>),
+}
+
+impl Padding {
+    /// Parse the content of a distribution file. See above for the format.
+    pub fn parse_distribution(content: &str) -> Result<Padding> {
+        let mut entries = Vec::new();
+        for line in content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut fields = line.split_whitespace();
+            let length = fields.next().unwrap().parse::<usize>()
+                .map_err(|e| anyhow::format_err!("Invalid length in `{}': {}", line, e))?;
+            let weight = fields.next().map_or(Ok(1), str::parse::<u32>)
+                .map_err(|e| anyhow::format_err!("Invalid weight in `{}': {}", line, e))?;
+            if fields.next().is_some() {
+                anyhow::bail!("Invalid line `{}'", line);
+            }
+            entries.push((length, weight));
+        }
+        if !entries.iter().any(|&(_, weight)| weight > 0) {
+            anyhow::bail!("Empty padding distribution");
+        }
+        entries.sort_unstable();
+        Ok(Padding::Distribution(entries))
+    }
+
+    /// Number of random bytes to add to a packet of min_len bytes (including the padding length trailer),
+    /// so that it's no longer than max_len
+    pub fn padding_len(&self, min_len: usize, max_len: usize, rng: &mut impl Rng) -> usize {
+        let room = max_len.saturating_sub(min_len);
+        let target = match self {
+            Padding::None => return 0,
+            Padding::Uniform => return rng.gen_range(0..=usize::min(UNIFORM_MAX, room)),
+            Padding::Full => return room,
+            Padding::Buckets(buckets) => buckets.iter().copied().find(|&x| x >= min_len).unwrap_or(max_len),
+            Padding::Distribution(entries) => {
+                let candidates = &entries[entries.partition_point(|&(length, _)| length < min_len)..];
+                match WeightedIndex::new(candidates.iter().map(|&(_, weight)| weight)) {
+                    Ok(index) => candidates[index.sample(rng)].0,
+                    Err(_) => return 0,
+                }
+            },
+        };
+        usize::min(target, max_len).saturating_sub(min_len)
+    }
+}
+
+impl std::str::FromStr for Padding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        match (name, arg) {
+            ("none", None) => Ok(Padding::None),
+            ("uniform", None) => Ok(Padding::Uniform),
+            ("full", None) => Ok(Padding::Full),
+            ("buckets", Some(arg)) => {
+                let mut buckets = arg.split(',')
+                    .map(|x| x.trim().parse::<usize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| anyhow::format_err!("Invalid buckets {}: {}", arg, e))?;
+                buckets.sort_unstable();
+                Ok(Padding::Buckets(buckets))
+            },
+            ("distribution", Some(path)) => Self::parse_distribution(&std::fs::read_to_string(path)
+                .map_err(|e| anyhow::format_err!("Cannot read {}: {}", path, e))?),
+            _ => anyhow::bail!("Unknown padding policy {}, available: none, uniform, buckets:SIZES, full, \
+                                distribution:FILE", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Padding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Padding::None => f.write_str("none"),
+            Padding::Uniform => f.write_str("uniform"),
+            Padding::Full => f.write_str("full"),
+            Padding::Buckets(buckets) => write!(
+                f, "buckets:{}", buckets.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",")),
+            Padding::Distribution(entries) => write!(f, "distribution ({} entries)", entries.len()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(Padding::None.padding_len(100, 1000, &mut rng), 0);
+        assert_eq!(Padding::Full.padding_len(100, 1000, &mut rng), 900);
+        assert_eq!(Padding::Full.padding_len(1000, 1000, &mut rng), 0);
+        for _ in 0..100 {
+            assert!(Padding::Uniform.padding_len(100, 1000, &mut rng) <= 255);
+            assert!(Padding::Uniform.padding_len(900, 1000, &mut rng) <= 100);
+        }
+
+        let buckets: Padding = "buckets:512,256".parse().unwrap();
+        assert_eq!(buckets.padding_len(100, 1000, &mut rng), 156);
+        assert_eq!(buckets.padding_len(256, 1000, &mut rng), 0);
+        assert_eq!(buckets.padding_len(257, 1000, &mut rng), 255);
+        assert_eq!(buckets.padding_len(600, 1000, &mut rng), 400);
+        assert_eq!(buckets.padding_len(600, 500, &mut rng), 0);
+    }
+
+    #[test]
+    fn test_distribution() {
+        let padding = Padding::parse_distribution("# comment\n300\n\n200 3\n1200 0\n100\n").unwrap();
+        assert_eq!(padding, Padding::Distribution(vec![(100, 1), (200, 3), (300, 1), (1200, 0)]));
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let len = 150 + padding.padding_len(150, 1000, &mut rng);
+            assert!(len == 200 || len == 300);
+        }
+        assert_eq!(padding.padding_len(250, 1000, &mut rng), 50);
+        // nothing fits, zero weight entries are never picked
+        assert_eq!(padding.padding_len(301, 1000, &mut rng), 0);
+
+        assert!(Padding::parse_distribution("").is_err());
+        assert!(Padding::parse_distribution("100 0").is_err());
+        assert!(Padding::parse_distribution("100 x").is_err());
+        assert!(Padding::parse_distribution("100 1 1").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("none".parse::<Padding>().unwrap(), Padding::None);
+        assert_eq!("uniform".parse::<Padding>().unwrap(), Padding::Uniform);
+        assert_eq!("full".parse::<Padding>().unwrap(), Padding::Full);
+        assert_eq!("buckets:128, 64".parse::<Padding>().unwrap(), Padding::Buckets(vec![64, 128]));
+        assert!("buckets".parse::<Padding>().is_err());
+        assert!("buckets:a".parse::<Padding>().is_err());
+        assert!("full:1".parse::<Padding>().is_err());
+        assert!("foo".parse::<Padding>().is_err());
+        assert!("distribution:/nonexistent".parse::<Padding>().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::info;
+
+use crate::cipher::Keys;
+use crate::config::IpNet;
+use crate::kdf::KeyConfig;
+
+// Peers file, for the server to authenticate each client with its own key:
+//
+//     # name   [allowed-ips=...]                  key
+//     alice    allowed-ips=10.9.0.2/32           some long passphrase
+//     bob      allowed-ips=10.9.0.3,10.1.0.0/16  @bob.key
+//
+// Each line has a name, and the key of that client: either a passphrase, or `@` followed by the path
+// of a key file (same as `--key`, relative to the peers file), which may configure a KDF.
+// The server identifies the client of a handshake by which key can decrypt it.
+//
+// Allowed IPs are the inner addresses the client may use as source, and that are routed to it
+// (in addition to the addresses assigned from the pool, if any). Ranges must not overlap between peers.
+// Packets of a peer from other addresses are dropped, so a peer without allowed IPs needs the pool (`--pool`).
+// Peers files of versions before allowed IPs were enforced need `allowed-ips=` on each line
+// (e.g. `allowed-ips=0.0.0.0/0,::/0` if there's a single peer, which may use any address as before).
+//
+// To revoke a client, remove (or comment out) its line. The file is reloaded when it's modified,
+// and sessions of removed or changed peers are dropped. Touch the file after changing a key file it refers to.
+
+/// A peer we can handshake with, identified by its static keys
+pub struct Peer {
+    pub name: String,
+    pub keys: Keys,
+    /// Inner source addresses accepted from the peer, and destinations routed to it
+    pub allowed_ips: Vec<IpNet>,
+}
+
+/// Peers of this end. For the client, it's only the server.
+/// For the server, either a single anonymous peer (from `--key`), or loaded from a peers file.
+pub struct Peers {
+    file: Option<PeersFile>,
+    list: Vec<Arc<Peer>>,
+}
+
+struct PeersFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    /// (name, rest of the line) -> peer, so that unchanged entries keep their identity (and sessions)
+    /// across reloads without running the KDF again
+    entries: HashMap<(String, String), Arc<Peer>>,
+}
+
+/// Return the name and the rest of the line
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    Some((name, rest.trim()))
+}
+
+/// Split the options from the key
+fn parse_options(rest: &str) -> Result<(Vec<IpNet>, &str)> {
+    let Some(value) = rest.strip_prefix("allowed-ips=") else {
+        return Ok((Vec::new(), rest));
+    };
+    let (value, key) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+    let allowed_ips = value.split(',')
+        .map(|x| x.parse().map_err(|e| anyhow::format_err!("Invalid allowed IPs {}: {}", x, e)))
+        .collect::<Result<_>>()?;
+    Ok((allowed_ips, key.trim()))
+}
+
+impl Peers {
+    /// A single peer, allowed to use any address
+    pub fn single(name: &str, keys: Keys) -> Peers {
+        let allowed_ips = vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()];
+        Peers {
+            file: None,
+            list: vec![Arc::new(Peer { name: name.to_owned(), keys, allowed_ips })],
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Peers> {
+        let mut file = PeersFile {
+            path: path.to_owned(),
+            modified: None,
+            entries: HashMap::new(),
+        };
+        let list = file.load()?;
+        Ok(Peers { file: Some(file), list })
+    }
+
+    pub fn list(&self) -> &[Arc<Peer>] {
+        &self.list
+    }
+
+    /// Reload the peers file if it's modified since last load. Return true if reloaded.
+    /// On error, the current peers are kept.
+    pub fn reload_if_modified(&mut self) -> Result<bool> {
+        let Some(file) = &mut self.file else {
+            return Ok(false);
+        };
+        if std::fs::metadata(&file.path)?.modified().ok() == file.modified {
+            return Ok(false);
+        }
+        self.list = file.load()?;
+        info!("Reloaded peers file {}, {} peers", file.path.display(), self.list.len());
+        Ok(true)
+    }
+}
+
+impl PeersFile {
+    fn load(&mut self) -> Result<Vec<Arc<Peer>>> {
+        // even if it fails, so that the error is only reported once for each modification
+        self.modified = std::fs::metadata(&self.path)?.modified().ok();
+        let content = std::fs::read_to_string(&self.path)
+            .map_err(|e| anyhow::format_err!("Cannot read peers file {}: {}", self.path.display(), e))?;
+        let base_dir = self.path.parent().unwrap_or(Path::new("."));
+
+        let mut entries = HashMap::new();
+        let mut list = Vec::new();
+        for (name, rest) in content.lines().filter_map(parse_line) {
+            if list.iter().any(|x: &Arc<Peer>| x.name == name) {
+                anyhow::bail!("Duplicated peer {}", name);
+            }
+            let (allowed_ips, key) = parse_options(rest)
+                .map_err(|e| anyhow::format_err!("Invalid line for peer {}: {}", name, e))?;
+            if key.is_empty() {
+                anyhow::bail!("No key for peer {}", name);
+            }
+            for net in &allowed_ips {
+                if let Some(other) = list.iter().find(|x| x.allowed_ips.iter().any(|x| x.overlaps(net))) {
+                    anyhow::bail!("Allowed IPs {} of peer {} are also allowed for peer {}", net, name, other.name);
+                }
+            }
+            let entry = (name.to_owned(), rest.to_owned());
+            let peer = match self.entries.get(&entry) {
+                Some(peer) => peer.clone(),
+                None => {
+                    let key_config = match key.strip_prefix('@') {
+                        Some(key_path) => KeyConfig::from_file(&base_dir.join(key_path))?,
+                        None => KeyConfig::from_passphrase(key),
+                    };
+                    let keys = key_config.derive_keys()
+                        .map_err(|e| anyhow::format_err!("Invalid key for peer {}: {}", name, e))?;
+                    Arc::new(Peer { name: name.to_owned(), keys, allowed_ips })
+                },
+            };
+            list.push(peer.clone());
+            entries.insert(entry, peer);
+        }
+
+        self.entries = entries;
+        Ok(list)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_and_reload() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("kissvpn-peers-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("peers");
+        std::fs::write(dir.join("bob.key"), "bob passphrase\n")?;
+        std::fs::write(&path, "# comment\nalice  alice passphrase\n\nbob allowed-ips=10.9.0.3,fd00::/64 @bob.key\n")?;
+
+        let mut peers = Peers::load(&path)?;
+        let names: Vec<_> = peers.list().iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert!(peers.list()[0].allowed_ips.is_empty());
+        assert_eq!(peers.list()[1].allowed_ips, ["10.9.0.3/32".parse()?, "fd00::/64".parse()?]);
+        assert!(!peers.reload_if_modified()?);
+
+        let alice = peers.list()[0].clone();
+        let bob = peers.list()[1].clone();
+        // revoke alice, add carol
+        std::fs::write(&path, "bob allowed-ips=10.9.0.3,fd00::/64 @bob.key\ncarol carol passphrase\n")?;
+        std::fs::File::options().write(true).open(&path)?
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(1))?;
+        assert!(peers.reload_if_modified()?);
+        let names: Vec<_> = peers.list().iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["bob", "carol"]);
+        assert!(!peers.list().iter().any(|x| Arc::ptr_eq(x, &alice)));
+        // unchanged entry is kept as is
+        assert!(Arc::ptr_eq(&peers.list()[0], &bob));
+
+        // invalid file keeps the current peers
+        std::fs::write(&path, "bob\n")?;
+        std::fs::File::options().write(true).open(&path)?
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(2))?;
+        assert!(peers.reload_if_modified().is_err());
+        assert_eq!(peers.list().len(), 2);
+
+        for content in ["bob x\nbob y\n", "bob allowed-ips=10.9.0.0/33 x\n", "bob allowed-ips=10.9.0.3\n",
+                        "bob allowed-ips=10.9.0.3 x\ncarol allowed-ips=10.9.0.3 y\n",
+                        "bob allowed-ips=10.1.0.0/16 x\ncarol allowed-ips=10.0.0.0/8 y\n"] {
+            std::fs::write(&path, content)?;
+            assert!(Peers::load(&path).is_err());
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use log::info;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::prctl;
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+// Dropping root privileges once the tun device and the sockets are set up: switch to another user and group,
+// keeping only some capabilities, e.g. CAP_NET_ADMIN to apply the tun config pushed by the server
+// and to mark new sockets (see constants::FWMARK).
+//
+// Capabilities are per thread, so this must be done before the engine starts its threads.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Capability {
+    NetBindService,
+    NetAdmin,
+    NetRaw,
+}
+
+const ALL_CAPABILITIES: &[Capability] = &[Capability::NetBindService, Capability::NetAdmin, Capability::NetRaw];
+
+impl Capability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::NetBindService => "net_bind_service",
+            Capability::NetAdmin => "net_admin",
+            Capability::NetRaw => "net_raw",
+        }
+    }
+
+    /// From linux/capability.h
+    fn number(self) -> u32 {
+        match self {
+            Capability::NetBindService => 10,
+            Capability::NetAdmin => 12,
+            Capability::NetRaw => 13,
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.to_ascii_lowercase();
+        let name = name.strip_prefix("cap_").unwrap_or(&name);
+        ALL_CAPABILITIES.iter().copied().find(|x| x.name() == name)
+            .ok_or(anyhow::format_err!("Unknown capability {}, available: {}", s,
+                                       ALL_CAPABILITIES.iter().map(|x| x.name()).collect::<Vec<_>>().join(", ")))
+    }
+}
+
+fn capability_mask(caps: &[Capability]) -> u64 {
+    caps.iter().fold(0, |mask, x| mask | 1 << x.number())
+}
+
+/// A user name or uid, with its primary group
+pub fn lookup_user(name: &str) -> Result<(Uid, Gid)> {
+    let user = match name.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+        Err(_) => User::from_name(name)?,
+    };
+    let user = user.ok_or(anyhow::format_err!("Unknown user {}", name))?;
+    Ok((user.uid, user.gid))
+}
+
+/// A group name or gid
+pub fn lookup_group(name: &str) -> Result<Gid> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    let group = Group::from_name(name)?.ok_or(anyhow::format_err!("Unknown group {}", name))?;
+    Ok(group.gid)
+}
+
+pub struct PrivilegeOptions {
+    pub user: String,
+    /// The primary group of the user if None
+    pub group: Option<String>,
+    pub keep_caps: Vec<Capability>,
+}
+
+/// Switch to the user and group, with only the capabilities to keep (effective and permitted, not inherited).
+/// Must be called before other threads are started.
+pub fn drop_privileges(options: &PrivilegeOptions) -> Result<()> {
+    let (uid, primary_gid) = lookup_user(&options.user)?;
+    let gid = match &options.group {
+        Some(group) => lookup_group(group)?,
+        None => primary_gid,
+    };
+    let caps: Vec<&str> = options.keep_caps.iter().map(|x| x.name()).collect();
+    info!("Dropping privileges to uid {} gid {}, keeping capabilities: {}", uid, gid,
+          if caps.is_empty() { "none".to_owned() } else { caps.join(", ") });
+
+    unistd::setgroups(&[gid])?;
+    unistd::setgid(gid)?;
+    // otherwise setuid clears the permitted capabilities
+    prctl::set_keepcaps(true)?;
+    unistd::setuid(uid)?;
+    prctl::set_keepcaps(false)?;
+    set_capabilities(capability_mask(&options.keep_caps))
+}
+
+// capset(2), not in libc
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn set_capabilities(mask: u64) -> Result<()> {
+    let header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    // 64 bits, in 2 halves
+    let data = [mask as u32, (mask >> 32) as u32].map(|x| CapData { effective: x, permitted: x, inheritable: 0 });
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &header as *const CapHeader, data.as_ptr()) };
+    Errno::result(ret)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!("net_admin".parse::<Capability>().unwrap(), Capability::NetAdmin);
+        assert_eq!("CAP_NET_RAW".parse::<Capability>().unwrap(), Capability::NetRaw);
+        assert!("sys_admin".parse::<Capability>().is_err());
+        assert_eq!(capability_mask(&[Capability::NetAdmin, Capability::NetBindService]), 1 << 12 | 1 << 10);
+        assert_eq!(capability_mask(&[]), 0);
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (Uid::from_raw(0), Gid::from_raw(0)));
+        assert_eq!(lookup_user("0").unwrap().0, Uid::from_raw(0));
+        assert_eq!(lookup_group("1234").unwrap(), Gid::from_raw(1234));
+        assert!(lookup_user("no-such-user-kissvpn").is_err());
+    }
+}
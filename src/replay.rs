@@ -0,0 +1,104 @@
+// Sliding window replay filter, see RFC 6479.
+// This is the same algorithm used by WireGuard.
+
+const BITS_PER_WORD: u64 = 64;
+const WINDOW_WORDS: usize = 32;
+
+/// Number of counters behind the largest seen one that can still be tracked.
+/// One word is reserved so that advancing the window never clears a live bit.
+pub const WINDOW_SIZE: u64 = (WINDOW_WORDS as u64 - 1) * BITS_PER_WORD;
+
+#[derive(Clone, Default)]
+pub struct ReplayWindow {
+    /// largest counter accepted so far
+    last: u64,
+    bitmap: [u64; WINDOW_WORDS],
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return true if the counter is new and mark it as seen.
+    /// Return false if the counter was already seen, or is too old to tell.
+    /// Must only be called for authenticated packets.
+    pub fn check_and_update(&mut self, counter: u64) -> bool {
+        if counter.saturating_add(WINDOW_SIZE) < self.last {
+            return false;
+        }
+
+        let index = counter / BITS_PER_WORD;
+        if counter > self.last {
+            let current_index = self.last / BITS_PER_WORD;
+            let diff = u64::min(index - current_index, WINDOW_WORDS as u64);
+            for i in 0..diff {
+                self.bitmap[((current_index + i + 1) % WINDOW_WORDS as u64) as usize] = 0;
+            }
+            self.last = counter;
+        }
+
+        let word = &mut self.bitmap[(index % WINDOW_WORDS as u64) as usize];
+        let bit = 1u64 << (counter % BITS_PER_WORD);
+        if *word & bit != 0 {
+            return false;
+        }
+        *word |= bit;
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates() {
+        let mut window = ReplayWindow::new();
+        assert!(window.check_and_update(0));
+        assert!(!window.check_and_update(0));
+        assert!(window.check_and_update(1));
+        assert!(window.check_and_update(3));
+        assert!(window.check_and_update(2));
+        assert!(!window.check_and_update(1));
+        assert!(!window.check_and_update(2));
+        assert!(!window.check_and_update(3));
+    }
+
+    #[test]
+    fn test_window_edges() {
+        let mut window = ReplayWindow::new();
+        let base = 1_000_000_000u64;
+        assert!(window.check_and_update(base));
+
+        // oldest trackable counter
+        assert!(window.check_and_update(base - WINDOW_SIZE));
+        assert!(!window.check_and_update(base - WINDOW_SIZE));
+        // too old
+        assert!(!window.check_and_update(base - WINDOW_SIZE - 1));
+
+        // jump far ahead, everything before is forgotten but too old anyway
+        assert!(window.check_and_update(base * 2));
+        assert!(!window.check_and_update(base));
+        assert!(window.check_and_update(base * 2 - 1));
+        assert!(!window.check_and_update(base * 2 - 1));
+    }
+
+    #[test]
+    fn test_reordering_within_window() {
+        let mut window = ReplayWindow::new();
+        for i in (0..WINDOW_SIZE).rev() {
+            assert!(window.check_and_update(WINDOW_SIZE * 4 + i));
+        }
+        for i in 0..WINDOW_SIZE {
+            assert!(!window.check_and_update(WINDOW_SIZE * 4 + i));
+        }
+        for i in (WINDOW_SIZE * 5..WINDOW_SIZE * 6).step_by(3) {
+            assert!(window.check_and_update(i));
+        }
+        for i in (WINDOW_SIZE * 5..WINDOW_SIZE * 6).step_by(3) {
+            assert!(!window.check_and_update(i));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::config::IpNet;
+
+/// Longest prefix match of addresses to values (peers), for cryptokey routing.
+/// Lookup is a hash lookup for each distinct prefix length in use.
+pub struct RoutingTable<V> {
+    routes: HashMap<IpNet, V>,
+    /// Prefix lengths in use, longest first
+    prefixes_v4: Vec<u8>,
+    prefixes_v6: Vec<u8>,
+}
+
+impl<V> Default for RoutingTable<V> {
+    fn default() -> Self {
+        RoutingTable { routes: HashMap::new(), prefixes_v4: Vec::new(), prefixes_v6: Vec::new() }
+    }
+}
+
+impl<V> RoutingTable<V> {
+    /// Return the value replaced, if the same network was already routed
+    pub fn insert(&mut self, net: IpNet, value: V) -> Option<V> {
+        let net = net.network();
+        let prefixes = if net.addr.is_ipv4() { &mut self.prefixes_v4 } else { &mut self.prefixes_v6 };
+        if let Err(pos) = prefixes.binary_search_by(|x| net.prefix.cmp(x)) {
+            prefixes.insert(pos, net.prefix);
+        }
+        self.routes.insert(net, value)
+    }
+
+    pub fn lookup(&self, addr: IpAddr) -> Option<&V> {
+        let prefixes = if addr.is_ipv4() { &self.prefixes_v4 } else { &self.prefixes_v6 };
+        prefixes.iter().find_map(|&prefix| {
+            let net = IpNet { addr, prefix }.network();
+            self.routes.get(&net)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut table = RoutingTable::default();
+        let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(table.lookup(addr("10.9.0.2")), None);
+
+        table.insert("0.0.0.0/0".parse().unwrap(), "default");
+        table.insert("10.9.0.0/24".parse().unwrap(), "net");
+        table.insert("10.9.0.2".parse().unwrap(), "host");
+        table.insert("fd00::/64".parse().unwrap(), "net6");
+        assert_eq!(table.lookup(addr("10.9.0.2")), Some(&"host"));
+        assert_eq!(table.lookup(addr("10.9.0.3")), Some(&"net"));
+        assert_eq!(table.lookup(addr("192.168.0.1")), Some(&"default"));
+        assert_eq!(table.lookup(addr("fd00::1")), Some(&"net6"));
+        assert_eq!(table.lookup(addr("fd01::1")), None);
+
+        // host bits are ignored
+        assert_eq!(table.insert("10.9.0.7/24".parse().unwrap(), "net2"), Some("net"));
+        assert_eq!(table.lookup(addr("10.9.0.3")), Some(&"net2"));
+    }
+}
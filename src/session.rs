@@ -0,0 +1,1220 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::BytesMut;
+use log::{debug, info, warn};
+
+use crate::cipher::{Cipher, Role};
+use crate::config::{IpNet, PushConfig, TunConfig};
+use crate::constants::{BUF_CAPACITY, ETHERNET_HEADER_SIZE};
+use crate::handshake::{self, Initiator, Responder, HANDSHAKE_SUITE};
+use crate::packet::{self, MacAddr};
+use crate::padding::Padding;
+use crate::peers::{Peer, Peers};
+use crate::replay::ReplayWindow;
+use crate::routing::RoutingTable;
+use crate::suite::Suite;
+use crate::version;
+
+/// Client retries the handshake if there's no response after this duration
+const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Client starts a new handshake if data was sent but nothing was received for this duration
+/// (e.g. the server restarted and lost the session)
+const UNANSWERED_TIMEOUT: Duration = Duration::from_secs(15);
+/// Server drops the session if nothing was received for this duration.
+/// Should be longer than the keepalive interval.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+/// Server keeps sessions of at most this many instances of a peer, the least recently active is dropped
+const MAX_INSTANCES_PER_PEER: usize = 16;
+/// Server in TAP mode learns at most this many MAC addresses per instance (and for its own side),
+/// frames from further addresses are still delivered, but replies to them are flooded
+const MAX_MACS_PER_OWNER: usize = 256;
+
+/// Server: handshake init replay windows kept per peer, of the instances seen most recently
+const MAX_INIT_WINDOWS_PER_PEER: usize = 4 * MAX_INSTANCES_PER_PEER;
+
+pub struct SessionOptions {
+    /// Client starts a new handshake after this many packets (sent and received) in current session
+    pub rekey_after_packets: u64,
+    /// Client starts a new handshake after this many bytes (sent and received) in current session
+    pub rekey_after_bytes: u64,
+    /// Client starts a new handshake after current session lasts this long
+    pub rekey_after_time: Duration,
+    /// After switching to a new session, the previous one is still accepted for decryption for this long,
+    /// so that packets in flight are not dropped
+    pub rekey_grace_period: Duration,
+    /// Cipher suite for data packets. Must be the same on both ends.
+    pub suite: Suite,
+    /// Padding policy for all packets sent, including handshake messages
+    pub padding: Arc<Padding>,
+    /// Server only: address pools and tun config pushed to clients that support it
+    pub push: Option<Arc<PushConfig>>,
+    /// Packets are Ethernet frames (TAP mode) instead of IP packets. Must be the same on both ends.
+    pub tap: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            // random 96-bit nonces should not be used for much more than 2^32 packets per key
+            rekey_after_packets: 1 << 30,
+            rekey_after_bytes: 1 << 36,
+            rekey_after_time: Duration::from_secs(10 * 60),
+            rekey_grace_period: Duration::from_secs(30),
+            suite: Suite::default(),
+            padding: Arc::new(Padding::default()),
+            push: None,
+            tap: false,
+        }
+    }
+}
+
+struct Session {
+    cipher: Cipher,
+    created: Instant,
+    last_received: Instant,
+    /// packets and bytes sent and received in this session
+    packets: u64,
+    bytes: u64,
+}
+
+impl Session {
+    fn new(cipher: Cipher) -> Session {
+        let now = Instant::now();
+        Session { cipher, created: now, last_received: now, packets: 0, bytes: 0 }
+    }
+
+    fn account(&mut self, len: usize) {
+        self.packets += 1;
+        self.bytes += len as u64;
+    }
+}
+
+/// Identifies a client instance: its peer, and the random id it sends in the handshake
+/// (see version::FEATURE_INSTANCE_ID), 0 for clients without the feature
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct InstanceKey {
+    peer: String,
+    instance: u64,
+}
+
+impl std::fmt::Display for InstanceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.instance {
+            0 => write!(f, "{}", self.peer),
+            x => write!(f, "{} (instance {:016x})", self.peer, x),
+        }
+    }
+}
+
+/// Where frames to a MAC address go, in TAP mode
+#[derive(Clone, PartialEq)]
+enum MacOwner {
+    /// The server's own side of the tap device
+    Local,
+    Peer(InstanceKey),
+}
+
+impl std::fmt::Display for MacOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacOwner::Local => write!(f, "the server"),
+            MacOwner::Peer(key) => write!(f, "peer {}", key),
+        }
+    }
+}
+
+/// Target of a route
+#[derive(Clone, PartialEq)]
+struct Route {
+    key: InstanceKey,
+    /// Assigned from the pool to this instance, rather than in the allowed IPs of its peer
+    assigned: bool,
+}
+
+/// Sessions with one client instance (for the client: with the server)
+struct State<A> {
+    peer: Arc<Peer>,
+    /// Where the last authenticated packet from the peer came from, so where to send to.
+    /// Not updated by a handshake init (which may be replayed), only once its session is confirmed.
+    addr: Option<A>,
+    current: Option<Session>,
+    /// Server only: responded to a handshake, waiting for the first packet to confirm it.
+    /// Until then, the current session is kept, so a replayed handshake cannot break it.
+    next: Option<Session>,
+    /// Replaced session that is still accepted for decryption, with the time it expires
+    previous: Option<(Session, Instant)>,
+    /// Client only: handshake in progress, with the time it's sent
+    initiator: Option<(Initiator, Instant)>,
+    /// Client only: time of the first data packet sent after last receive
+    unanswered_since: Option<Instant>,
+    /// Server only: name of the instance in the pool, the peer name for its first instance
+    pool_name: String,
+    /// Server only: addresses assigned to the instance from the pool
+    addresses: Vec<IpNet>,
+}
+
+impl<A> State<A> {
+    fn new(peer: Arc<Peer>) -> State<A> {
+        State {
+            peer,
+            addr: None,
+            current: None,
+            next: None,
+            previous: None,
+            initiator: None,
+            unanswered_since: None,
+            pool_name: String::new(),
+            addresses: Vec::new(),
+        }
+    }
+
+    fn replace_current(&mut self, session: Session, grace_period: Duration) {
+        if let Some(old) = self.current.replace(session) {
+            self.previous = Some((old, Instant::now() + grace_period));
+        }
+    }
+
+    fn ciphers(&self) -> impl Iterator<Item = (Slot, Cipher)> + '_ {
+        [
+            (Slot::Current, self.current.as_ref()),
+            (Slot::Next, self.next.as_ref()),
+            (Slot::Previous, self.previous.as_ref().map(|x| &x.0)),
+        ].into_iter().filter_map(|(slot, session)| Some((slot, session?.cipher.clone())))
+    }
+
+    /// When the last packet was received from the instance, or the handshake was received
+    fn last_active(&self) -> Option<Instant> {
+        [&self.current, &self.next].into_iter().flatten().map(|x| x.last_received).max()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.current.is_none() && self.next.is_none() && self.previous.is_none() && self.initiator.is_none()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Current,
+    Next,
+    Previous,
+}
+
+pub enum Received {
+    /// Decrypted data packet. Empty for keepalive.
+    Data(BytesMut),
+    /// Control message handled, maybe with an encrypted reply to send back,
+    /// and (client only) the tun config pushed by the server
+    Control {
+        reply: Option<BytesMut>,
+        config: Option<TunConfig>,
+    },
+}
+
+/// Owns the per-session ciphers of all peers.
+/// Control messages (handshake) are encrypted with the static keys of the peer,
+/// data packets are encrypted with the session keys.
+///
+/// `A` is the address of a peer in the transport. Packets to a peer are sent to where its last
+/// authenticated packet came from, so clients can change address (e.g. NAT rebinding).
+///
+/// The server keeps sessions for each client instance (identified by the id it sends in the handshake),
+/// so several clients may share a key (e.g. `--key`), each getting its own addresses from the pool.
+/// A restarted client is a new instance, the session of the old one expires when idle.
+///
+/// Cryptokey routing: each peer has allowed IPs (from the peers file), and each instance the addresses assigned
+/// from the pool. The server sends a packet read from the tun to the instance it's assigned to,
+/// or else to the most recently established instance of the peer whose allowed IPs contain its destination.
+/// A packet received from an instance is only accepted if its source routes back to that same instance
+/// (for assigned addresses) or peer.
+///
+/// In TAP mode, the server is a learning switch instead: frames are sent to the peer their destination
+/// MAC address was seen from, or to all peers for broadcast, multicast and unknown destinations.
+/// Allowed IPs are not enforced (frames may not even be IP), and frames between clients are not
+/// forwarded by the server. A MAC address stays with its first owner while that instance has sessions,
+/// frames from other peers using it are dropped; addresses seen on the server's side of the tap always
+/// belong to it.
+pub struct SessionManager<A> {
+    role: Role,
+    options: SessionOptions,
+    peers: Mutex<Peers>,
+    /// Each peer with its static cipher. Control messages are tried with each of them,
+    /// which identifies the peer.
+    static_ciphers: RwLock<Vec<(Arc<Peer>, Cipher)>>,
+    states: Mutex<HashMap<InstanceKey, State<A>>>,
+    /// Server only: replay window of handshake inits per instance, with when it was last used.
+    /// Instances of a peer share its static key but each has its own counter, so one window per peer
+    /// would reject the instance started first. Kept apart from `states` to outlive idle sessions
+    init_windows: Mutex<HashMap<InstanceKey, (ReplayWindow, Instant)>>,
+    /// Allowed IPs and assigned addresses -> instance
+    routes: RwLock<RoutingTable<Route>>,
+    /// Server in TAP mode only: source MAC addresses of frames received (or read from the tap) -> owner.
+    /// Only has instances in `states`
+    macs: RwLock<HashMap<MacAddr, MacOwner>>,
+    /// Client only: id of this instance, sent in the handshake
+    instance: u64,
+}
+
+impl<A: Clone + PartialEq> SessionManager<A> {
+    /// For the client, `peers` should be only the server
+    pub fn new(peers: Peers, role: Role, options: SessionOptions) -> SessionManager<A> {
+        let manager = SessionManager {
+            role,
+            options,
+            peers: Mutex::new(peers),
+            static_ciphers: RwLock::new(Vec::new()),
+            states: Mutex::new(HashMap::new()),
+            init_windows: Mutex::new(HashMap::new()),
+            routes: RwLock::new(RoutingTable::default()),
+            macs: RwLock::new(HashMap::new()),
+            instance: rand::random::<u64>().max(1),
+        };
+        manager.update_static_ciphers();
+        manager
+    }
+
+    fn update_static_ciphers(&self) {
+        let peers = self.peers.lock().unwrap().list().to_vec();
+        let mut static_ciphers = self.static_ciphers.write().unwrap();
+        let has_pool = self.options.push.as_ref().is_some_and(|x| x.has_pool());
+        // keep the cipher of unchanged peers, with its replay window
+        *static_ciphers = peers.iter()
+            .map(|peer| {
+                let cipher = static_ciphers.iter().find(|(x, _)| Arc::ptr_eq(x, peer))
+                    .map(|(_, cipher)| cipher.clone())
+                    .unwrap_or_else(|| {
+                        if self.role == Role::Server && !self.options.tap && !has_pool && peer.allowed_ips.is_empty() {
+                            warn!("Peer {} has no allowed IPs and there's no address pool, all its packets are dropped",
+                                  peer.name);
+                        }
+                        Cipher::new(&peer.keys, self.role, HANDSHAKE_SUITE).with_padding(self.options.padding.clone())
+                    });
+                (peer.clone(), cipher)
+            })
+            .collect();
+        drop(static_ciphers);
+        self.init_windows.lock().unwrap().retain(|key, _| peers.iter().any(|x| x.name == key.peer));
+
+        // drop sessions of peers that are no longer configured
+        let mut states = self.states.lock().unwrap();
+        states.retain(|key, state| {
+            let keep = peers.iter().any(|x| Arc::ptr_eq(x, &state.peer));
+            if !keep && state.current.is_some() {
+                info!("Peer {} removed, dropping its session", key);
+            }
+            keep
+        });
+        self.update_routes(&states);
+    }
+
+    /// To be called when instances come and go, or their current session is replaced
+    fn update_routes(&self, states: &HashMap<InstanceKey, State<A>>) {
+        let mut routes = RoutingTable::default();
+        for (peer, _) in self.static_ciphers.read().unwrap().iter() {
+            // the most recently established instance
+            let latest = states.iter()
+                .filter(|(key, _)| key.peer == peer.name)
+                .filter_map(|(key, state)| Some((state.current.as_ref()?.created, key)))
+                .max_by_key(|(created, _)| *created);
+            let Some((_, key)) = latest else {
+                continue;
+            };
+            for &net in &peer.allowed_ips {
+                routes.insert(net, Route { key: key.clone(), assigned: false });
+            }
+        }
+        for (key, state) in states {
+            for &net in &state.addresses {
+                let route = Route { key: key.clone(), assigned: true };
+                if let Some(other) = routes.insert(net, route).filter(|x| x.key.peer != key.peer) {
+                    warn!("Address {} assigned to peer {} is in the allowed IPs of peer {}", net, key, other.key);
+                }
+            }
+        }
+        *self.routes.write().unwrap() = routes;
+        self.macs.write().unwrap().retain(|_, owner| match owner {
+            MacOwner::Local => true,
+            MacOwner::Peer(key) => states.contains_key(key),
+        });
+    }
+
+    /// Server in TAP mode: learn the source MAC address of a frame, return false if it belongs to another owner
+    fn learn_mac(&self, buf: &[u8], owner: MacOwner) -> bool {
+        let Some(src) = packet::eth_source(buf).filter(|x| !packet::is_group_mac(x)) else {
+            return true;
+        };
+        if self.macs.read().unwrap().get(&src) == Some(&owner) {
+            return true;
+        }
+        let mut macs = self.macs.write().unwrap();
+        match macs.get(&src) {
+            Some(x) if *x == owner => return true,
+            // not taken from its owner, except by the server's side (a host may have moved from behind a peer)
+            Some(_) if owner != MacOwner::Local => return false,
+            _ => (),
+        }
+        if macs.values().filter(|x| **x == owner).count() >= MAX_MACS_PER_OWNER {
+            return true;
+        }
+        match macs.insert(src, owner.clone()) {
+            Some(previous) => warn!("MAC {:02x?} moved from {} to {}", src, previous, owner),
+            None => debug!("Learned MAC {:02x?} from {}", src, owner),
+        }
+        true
+    }
+
+    fn route(&self, buf: &[u8]) -> Option<InstanceKey> {
+        if self.options.tap {
+            let dst = packet::eth_destination(buf)?;
+            match self.macs.read().unwrap().get(&dst) {
+                Some(MacOwner::Peer(key)) => Some(key.clone()),
+                _ => None,
+            }
+        } else {
+            let dst = packet::destination_addr(buf)?;
+            self.routes.read().unwrap().lookup(dst).map(|x| x.key.clone())
+        }
+    }
+
+    /// Server in TAP mode: whether a frame must be sent to all peers with `encrypt_flood`,
+    /// because its destination is a group address or unknown
+    pub fn should_flood(&self, buf: &[u8]) -> bool {
+        self.role == Role::Server && self.options.tap && match packet::eth_destination(buf) {
+            Some(dst) => packet::is_group_mac(&dst) || !self.macs.read().unwrap().contains_key(&dst),
+            None => false,
+        }
+    }
+
+    /// Encrypt a copy of the packet for each peer with a session, return them with the address to send to
+    pub fn encrypt_flood(&self, buf: &[u8]) -> Result<Vec<(BytesMut, A)>> {
+        self.learn_mac(buf, MacOwner::Local);
+        let targets: Vec<(Cipher, A)> = {
+            let mut states = self.states.lock().unwrap();
+            states.values_mut()
+                .filter_map(|state| {
+                    let (Some(session), Some(addr)) = (&mut state.current, &state.addr) else {
+                        return None;
+                    };
+                    session.account(buf.len());
+                    Some((session.cipher.clone(), addr.clone()))
+                })
+                .collect()
+        };
+        targets.into_iter()
+            .map(|(cipher, addr)| {
+                let mut copy = BytesMut::with_capacity(BUF_CAPACITY);
+                copy.extend_from_slice(buf);
+                cipher.encrypt(&mut copy)?;
+                Ok((copy, addr))
+            })
+            .collect()
+    }
+
+    /// Encrypt a data packet (or keepalive, if empty) with the current session of the peer it's routed to.
+    /// Return the address to send it to, or None if there's no session (or no route),
+    /// in which case the packet should be dropped.
+    pub fn encrypt(&self, buf: &mut BytesMut) -> Result<Option<A>> {
+        let key = match self.role {
+            Role::Client => None,
+            Role::Server => {
+                if self.options.tap {
+                    self.learn_mac(buf, MacOwner::Local);
+                }
+                let Some(key) = self.route(buf) else {
+                    return Ok(None);
+                };
+                Some(key)
+            },
+        };
+
+        let (cipher, addr) = {
+            let mut states = self.states.lock().unwrap();
+            let state = match &key {
+                Some(key) => states.get_mut(key),
+                // the only state of the client
+                None => states.values_mut().next(),
+            };
+            let Some(state) = state else {
+                return Ok(None);
+            };
+            let (Some(session), Some(addr)) = (&mut state.current, &state.addr) else {
+                return Ok(None);
+            };
+            session.account(buf.len());
+            let result = (session.cipher.clone(), addr.clone());
+            if self.role == Role::Client && !buf.is_empty() && state.unanswered_since.is_none() {
+                state.unanswered_since = Some(Instant::now());
+            }
+            result
+        };
+        cipher.encrypt(buf)?;
+        Ok(Some(addr))
+    }
+
+    fn candidates(&self, filter: impl Fn(&State<A>) -> bool) -> Vec<(InstanceKey, Slot, Cipher)> {
+        let states = self.states.lock().unwrap();
+        states.iter()
+            .filter(|(_, state)| filter(state))
+            .flat_map(|(key, state)| state.ciphers().map(|(slot, cipher)| (key.clone(), slot, cipher)))
+            .collect()
+    }
+
+    /// Decrypt a packet received from `addr`
+    pub fn decrypt(&self, mut buf: BytesMut, addr: A) -> Result<Received> {
+        // try the peer last seen at this address first, then others (which may have moved)
+        let same_addr = |state: &State<A>| state.addr.as_ref() == Some(&addr);
+        for candidates in [self.candidates(same_addr), self.candidates(|x| !same_addr(x))] {
+            for (key, slot, cipher) in candidates {
+                if cipher.decrypt(&mut buf).is_ok() {
+                    self.on_data_received(&key, slot, &buf, addr);
+                    if !buf.is_empty() {
+                        self.check_source(&key, &buf)?;
+                    }
+                    return Ok(Received::Data(buf));
+                }
+            }
+        }
+
+        // O(number of peers), but only for control messages (and invalid packets)
+        let static_ciphers = self.static_ciphers.read().unwrap().clone();
+        for (peer, cipher) in static_ciphers {
+            // the server checks the counter once the instance is known, see `init_windows`
+            let decrypted = match self.role {
+                Role::Server => cipher.decrypt_unchecked(&mut buf).map(Some),
+                Role::Client => cipher.decrypt(&mut buf).map(|_| None),
+            };
+            if let Ok(counter) = decrypted {
+                return self.handle_control(peer, &cipher, &buf, counter, addr);
+            }
+        }
+        anyhow::bail!("Cannot decrypt with any session or peer key")
+    }
+
+    fn on_data_received(&self, key: &InstanceKey, slot: Slot, buf: &[u8], addr: A) {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(key) else {
+            return;
+        };
+        state.addr = Some(addr);
+        state.unanswered_since = None;
+        match slot {
+            Slot::Current => {
+                if let Some(session) = &mut state.current {
+                    session.last_received = now;
+                    session.account(buf.len());
+                }
+            },
+            Slot::Next => {
+                if let Some(mut session) = state.next.take() {
+                    info!("Session confirmed by peer {}", key);
+                    session.last_received = now;
+                    session.account(buf.len());
+                    state.replace_current(session, self.options.rekey_grace_period);
+                    self.update_routes(&states);
+                }
+            },
+            Slot::Previous => {},
+        }
+    }
+
+    /// Whether a data packet (not a keepalive) decrypted with a session of `key` may be delivered
+    fn check_source(&self, key: &InstanceKey, buf: &[u8]) -> Result<()> {
+        if !self.options.tap {
+            if !self.is_allowed_source(key, buf) {
+                anyhow::bail!("Source address {:?} not allowed for peer {}", packet::source_addr(buf), key);
+            }
+            return Ok(());
+        }
+        if buf.len() < ETHERNET_HEADER_SIZE {
+            anyhow::bail!("Frame of {} bytes from peer {} too short", buf.len(), key);
+        }
+        if self.role == Role::Server && !self.learn_mac(buf, MacOwner::Peer(key.clone())) {
+            anyhow::bail!("Source MAC {:02x?} of peer {} belongs to another", packet::eth_source(buf), key);
+        }
+        Ok(())
+    }
+
+    fn is_allowed_source(&self, key: &InstanceKey, buf: &[u8]) -> bool {
+        let Some(src) = packet::source_addr(buf) else {
+            return false;
+        };
+        match self.routes.read().unwrap().lookup(src) {
+            Some(route) if route.assigned => route.key == *key,
+            Some(route) => route.key.peer == key.peer,
+            None => false,
+        }
+    }
+
+    /// Name of a new instance of `peer` in the pool: the peer name for the first one,
+    /// then the name with the lowest number not used by other instances, so that addresses are reused
+    fn pool_name(states: &HashMap<InstanceKey, State<A>>, peer: &str) -> String {
+        let used = |name: &str| states.iter().any(|(key, state)| key.peer == peer && state.pool_name == name);
+        (0..).map(|i| if i == 0 { peer.to_owned() } else { format!("{}#{}", peer, i) })
+            .find(|name| !used(name))
+            .unwrap()
+    }
+
+    /// `counter` is that of the message, not yet checked for replay (server only)
+    fn handle_control(&self, peer: Arc<Peer>, static_cipher: &Cipher, msg: &[u8], counter: Option<u64>, addr: A)
+        -> Result<Received> {
+        match (self.role, msg.first()) {
+            (Role::Server, Some(&handshake::MSG_HANDSHAKE_INIT)) => {
+                // the message is authenticated, so failures here are most likely misconfiguration
+                let responder = Responder::new(self.options.suite, msg)
+                    .inspect_err(|e| warn!("Handshake with peer {} failed: {}", peer.name, e))?;
+                let key = InstanceKey { peer: peer.name.clone(), instance: responder.instance() };
+                if let Some(counter) = counter {
+                    self.check_init_replay(&key, counter)?;
+                }
+                let mut states = self.states.lock().unwrap();
+                if !states.contains_key(&key) {
+                    self.make_room_for_instance(&mut states, &peer.name);
+                }
+                let state = states.entry(key.clone()).or_insert_with(|| State::new(peer.clone()));
+                if !Arc::ptr_eq(&state.peer, &peer) {
+                    // the peer is replaced just now
+                    *state = State::new(peer.clone());
+                }
+                if state.pool_name.is_empty() {
+                    let pool_name = Self::pool_name(&states, &peer.name);
+                    states.get_mut(&key).unwrap().pool_name = pool_name;
+                }
+                let state = states.get_mut(&key).unwrap();
+                let config = match &self.options.push {
+                    Some(push) if responder.features() & version::FEATURE_TUN_CONFIG != 0 => Some(
+                        push.client_config(&state.pool_name)
+                            .inspect_err(|e| warn!("Cannot assign addresses to peer {}: {}", key, e))?),
+                    _ => None,
+                };
+                let payload = config.as_ref().map(TunConfig::encode).unwrap_or_default();
+                let (mut response, cipher) = responder.respond(&peer.keys, &payload)?;
+                debug!("Received handshake init from peer {}, sending response", key);
+                {
+                    // the response goes back to `addr`, but the current session keeps its address until confirmed
+                    state.next = Some(Session::new(cipher.with_padding(self.options.padding.clone())));
+                    let addresses: Vec<IpNet> = config.iter()
+                        .flat_map(|x| x.addresses.iter().map(|x| IpNet::from(x.addr)))
+                        .collect();
+                    if addresses != state.addresses {
+                        state.addresses = addresses;
+                        self.update_routes(&states);
+                    }
+                }
+                drop(states);
+                static_cipher.encrypt(&mut response)?;
+                Ok(Received::Control { reply: Some(response), config: None })
+            },
+            (Role::Client, Some(&handshake::MSG_HANDSHAKE_RESPONSE)) => {
+                let mut states = self.states.lock().unwrap();
+                let Some(state) = states.get_mut(&InstanceKey { peer: peer.name.clone(), instance: self.instance }) else {
+                    anyhow::bail!("Unexpected handshake response");
+                };
+                let Some((initiator, _)) = state.initiator.take() else {
+                    anyhow::bail!("Unexpected handshake response");
+                };
+                let (cipher, payload) = initiator.finish(&peer.keys, msg)
+                    .inspect_err(|e| warn!("Handshake failed: {}", e))?;
+                let cipher = cipher.with_padding(self.options.padding.clone());
+                info!("Session established, protocol version {}", cipher.version());
+                let config = if payload.is_empty() {
+                    None
+                } else {
+                    TunConfig::decode(&payload)
+                        .inspect_err(|e| warn!("Ignoring invalid tun config from the server: {}", e))
+                        .ok()
+                };
+                // confirm the session to the server with a keepalive right away
+                let mut keepalive = BytesMut::with_capacity(BUF_CAPACITY);
+                cipher.encrypt(&mut keepalive)?;
+                state.replace_current(Session::new(cipher), self.options.rekey_grace_period);
+                state.addr = Some(addr);
+                state.unanswered_since = None;
+                self.update_routes(&states);
+                Ok(Received::Control { reply: Some(keepalive), config })
+            },
+            _ => anyhow::bail!("Unexpected control message"),
+        }
+    }
+
+    /// Server: check the counter of a handshake init in the replay window of its instance
+    fn check_init_replay(&self, key: &InstanceKey, counter: u64) -> Result<()> {
+        let now = Instant::now();
+        let mut windows = self.init_windows.lock().unwrap();
+        if !windows.contains_key(key) {
+            let instances = windows.iter().filter(|(x, _)| x.peer == key.peer);
+            if instances.clone().count() >= MAX_INIT_WINDOWS_PER_PEER {
+                if let Some(oldest) = instances.min_by_key(|(_, (_, used))| *used).map(|(x, _)| x.clone()) {
+                    windows.remove(&oldest);
+                }
+            }
+        }
+        let (window, used) = windows.entry(key.clone()).or_insert_with(|| (ReplayWindow::new(), now));
+        if !window.check_and_update(counter) {
+            anyhow::bail!("Replayed handshake init from peer {}, counter = {}", key, counter);
+        }
+        *used = now;
+        Ok(())
+    }
+
+    /// Server: drop the least recently active instance of a peer that has too many
+    fn make_room_for_instance(&self, states: &mut HashMap<InstanceKey, State<A>>, peer: &str) {
+        let instances = states.iter().filter(|(key, _)| key.peer == peer);
+        if instances.clone().count() < MAX_INSTANCES_PER_PEER {
+            return;
+        }
+        let Some(oldest) = instances.min_by_key(|(_, state)| state.last_active()).map(|(key, _)| key.clone()) else {
+            return;
+        };
+        warn!("Too many instances of peer {}, dropping the session of {}", peer, oldest);
+        states.remove(&oldest);
+        self.update_routes(states);
+    }
+
+    fn needs_rekey(&self, session: &Session, now: Instant) -> bool {
+        session.packets >= self.options.rekey_after_packets
+            || session.bytes >= self.options.rekey_after_bytes
+            || now - session.created >= self.options.rekey_after_time
+    }
+
+    /// Should be called periodically.
+    /// Expire idle sessions, and return an encrypted handshake packet to send if a new session is required
+    /// (client only, to be sent to the server).
+    pub fn maintain(&self) -> Result<Option<BytesMut>> {
+        let reloaded = self.peers.lock().unwrap().reload_if_modified()
+            .inspect_err(|e| warn!("Failed to reload peers: {}", e));
+        if reloaded.is_ok_and(|x| x) {
+            self.update_static_ciphers();
+        }
+
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+
+        for (key, state) in states.iter_mut() {
+            if state.previous.as_ref().is_some_and(|(_, expires)| now >= *expires) {
+                debug!("Previous session of peer {} expired", key);
+                state.previous = None;
+            }
+        }
+
+        match self.role {
+            Role::Server => {
+                let mut changed = false;
+                for (key, state) in states.iter_mut() {
+                    if state.current.as_ref().is_some_and(|x| now - x.last_received > SESSION_IDLE_TIMEOUT) {
+                        info!("Session of peer {} idle for too long, dropping it", key);
+                        state.current = None;
+                        changed = true;
+                    }
+                    if state.next.as_ref().is_some_and(|x| now - x.last_received > SESSION_IDLE_TIMEOUT) {
+                        state.next = None;
+                    }
+                }
+                let len = states.len();
+                states.retain(|_, state| !state.is_empty());
+                if changed || states.len() != len {
+                    self.update_routes(&states);
+                }
+                Ok(None)
+            },
+            Role::Client => {
+                let Some((peer, static_cipher)) = self.static_ciphers.read().unwrap().first().cloned() else {
+                    anyhow::bail!("No server configured");
+                };
+                let key = InstanceKey { peer: peer.name.clone(), instance: self.instance };
+                let state = states.entry(key).or_insert_with(|| State::new(peer));
+                let need_handshake = match &state.current {
+                    None => true,
+                    Some(session) => self.needs_rekey(session, now),
+                } || state.unanswered_since.is_some_and(|t| now - t > UNANSWERED_TIMEOUT);
+                let handshake_pending = state.initiator.as_ref()
+                    .is_some_and(|(_, sent)| now - *sent < HANDSHAKE_RETRY_INTERVAL);
+                if !need_handshake || handshake_pending {
+                    return Ok(None);
+                }
+
+                debug!("Sending handshake init");
+                let initiator = Initiator::new(self.options.suite).with_instance(self.instance);
+                let mut init = initiator.init_message();
+                static_cipher.encrypt(&mut init)?;
+                state.initiator = Some((initiator, now));
+                Ok(Some(init))
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::cipher::Keys;
+
+    // the server identifies clients by a number in these tests
+    type Client = SessionManager<()>;
+    type Server = SessionManager<u32>;
+
+    const SERVER_IP: [u8; 4] = [10, 9, 0, 1];
+    const CLIENT_IP: [u8; 4] = [10, 9, 0, 2];
+
+    fn expect_control(received: Received) -> Option<BytesMut> {
+        match received {
+            Received::Control { reply, .. } => reply,
+            Received::Data(_) => panic!("expected control message"),
+        }
+    }
+
+    fn expect_data(received: Received) -> BytesMut {
+        match received {
+            Received::Data(buf) => buf,
+            Received::Control { .. } => panic!("expected data"),
+        }
+    }
+
+    fn ip_packet(src: [u8; 4], dst: [u8; 4], payload: &str) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(BUF_CAPACITY);
+        buf.extend_from_slice(&[0x45; 12]);
+        buf.extend_from_slice(&src);
+        buf.extend_from_slice(&dst);
+        buf.extend_from_slice(payload.as_bytes());
+        buf
+    }
+
+    fn to_server(payload: &str) -> BytesMut {
+        ip_packet(CLIENT_IP, SERVER_IP, payload)
+    }
+
+    fn to_client(payload: &str) -> BytesMut {
+        ip_packet(SERVER_IP, CLIENT_IP, payload)
+    }
+
+    fn frame(dst: MacAddr, src: MacAddr) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(BUF_CAPACITY);
+        buf.extend_from_slice(&dst);
+        buf.extend_from_slice(&src);
+        buf.extend_from_slice(&[0x08, 0x06, 1, 2, 3]);
+        buf
+    }
+
+    fn new_manager<A: Clone + PartialEq>(keys: &Keys, role: Role, options: SessionOptions) -> SessionManager<A> {
+        SessionManager::new(Peers::single("peer", keys.clone()), role, options)
+    }
+
+    fn establish(client: &Client, server: &Server, addr: u32) -> Result<()> {
+        let response = expect_control(server.decrypt(client.maintain()?.unwrap(), addr)?).unwrap();
+        let keepalive = expect_control(client.decrypt(response, ())?).unwrap();
+        expect_data(server.decrypt(keepalive, addr)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_establish_session() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let client: Client = new_manager(&keys, Role::Client, SessionOptions::default());
+        let server: Server = new_manager(&keys, Role::Server, SessionOptions::default());
+
+        // no session yet
+        assert!(client.encrypt(&mut to_server("hello"))?.is_none());
+        assert!(server.maintain()?.is_none());
+
+        let init = client.maintain()?.unwrap();
+        // not retrying immediately
+        assert!(client.maintain()?.is_none());
+
+        let response = expect_control(server.decrypt(init.clone(), 1)?).unwrap();
+        let keepalive = expect_control(client.decrypt(response, ())?).unwrap();
+        assert!(expect_data(server.decrypt(keepalive, 1)?).is_empty());
+        assert!(client.maintain()?.is_none());
+
+        let mut buf = to_server("world");
+        assert_eq!(client.encrypt(&mut buf)?, Some(()));
+        assert_eq!(expect_data(server.decrypt(buf, 1)?), to_server("world"));
+
+        let mut buf = to_client("hello");
+        assert_eq!(server.encrypt(&mut buf)?, Some(1));
+        assert_eq!(expect_data(client.decrypt(buf, ())?), to_client("hello"));
+
+        // a replayed handshake init is rejected, and doesn't redirect the session
+        assert!(server.decrypt(init, 2).is_err());
+        assert_eq!(server.encrypt(&mut to_client("hello"))?, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_instances_out_of_order() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let server: Server = new_manager(&keys, Role::Server, SessionOptions::default());
+        // both counters start from the clock, the first instance's is far behind once the second handshakes
+        let first: Client = new_manager(&keys, Role::Client, SessionOptions::default());
+        std::thread::sleep(Duration::from_millis(10));
+        let second: Client = new_manager(&keys, Role::Client, SessionOptions::default());
+
+        establish(&second, &server, 2)?;
+        let init = first.maintain()?.unwrap();
+        let response = expect_control(server.decrypt(init.clone(), 1)?).unwrap();
+        let keepalive = expect_control(first.decrypt(response, ())?).unwrap();
+        expect_data(server.decrypt(keepalive, 1)?);
+        // replays are still rejected per instance
+        assert!(server.decrypt(init, 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_not_confirmed() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let client: Client = new_manager(&keys, Role::Client, SessionOptions::default());
+        let server: Server = new_manager(&keys, Role::Server, SessionOptions::default());
+
+        let response = expect_control(server.decrypt(client.maintain()?.unwrap(), 1)?).unwrap();
+        expect_control(client.decrypt(response, ())?).unwrap();
+        // the client sends data before the keepalive arrives, which also confirms the session
+        let mut buf = to_server("hello");
+        assert!(client.encrypt(&mut buf)?.is_some());
+        assert_eq!(expect_data(server.decrypt(buf, 1)?), to_server("hello"));
+        assert!(server.encrypt(&mut to_client("hello"))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_passphrase() -> Result<()> {
+        let client: Client = new_manager(&Keys::derive("key0"), Role::Client, SessionOptions::default());
+        let server: Server = new_manager(&Keys::derive("key1"), Role::Server, SessionOptions::default());
+
+        let init = client.maintain()?.unwrap();
+        assert!(server.decrypt(init, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rehandshake_keeps_current_session() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let client: Client = new_manager(&keys, Role::Client, SessionOptions::default());
+        let server: Server = new_manager(&keys, Role::Server, SessionOptions::default());
+
+        establish(&client, &server, 1)?;
+        let mut buf = to_server("hello");
+        client.encrypt(&mut buf)?;
+        expect_data(server.decrypt(buf, 1)?);
+
+        // another client instance (e.g. restarted) handshakes, but doesn't confirm yet
+        let other_client: Client = new_manager(&keys, Role::Client, SessionOptions::default());
+        let response = expect_control(server.decrypt(other_client.maintain()?.unwrap(), 2)?).unwrap();
+
+        // server still talks to the old session, at its address
+        let mut buf = to_client("hello");
+        assert_eq!(server.encrypt(&mut buf)?, Some(1));
+        assert_eq!(expect_data(client.decrypt(buf, ())?), to_client("hello"));
+
+        // until the new one is confirmed
+        let keepalive = expect_control(other_client.decrypt(response, ())?).unwrap();
+        expect_data(server.decrypt(keepalive, 2)?);
+        let mut buf = to_client("hello");
+        assert_eq!(server.encrypt(&mut buf)?, Some(2));
+        assert!(client.decrypt(buf.clone(), ()).is_err());
+        assert_eq!(expect_data(other_client.decrypt(buf, ())?), to_client("hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_roaming() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let client: Client = new_manager(&keys, Role::Client, SessionOptions::default());
+        let server: Server = new_manager(&keys, Role::Server, SessionOptions::default());
+        establish(&client, &server, 1)?;
+
+        // the client's address changed, e.g. NAT rebinding
+        let mut buf = to_server("hello");
+        client.encrypt(&mut buf)?;
+        expect_data(server.decrypt(buf, 2)?);
+        assert_eq!(server.encrypt(&mut to_client("hello"))?, Some(2));
+
+        // not for invalid packets
+        assert!(server.decrypt(to_server("hello"), 3).is_err());
+        assert_eq!(server.encrypt(&mut to_client("hello"))?, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_with_grace_period() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let options = || SessionOptions {
+            rekey_after_packets: 4,
+            ..Default::default()
+        };
+        let client: Client = new_manager(&keys, Role::Client, options());
+        let server: Server = new_manager(&keys, Role::Server, options());
+        establish(&client, &server, 1)?;
+
+        // 3 sent, 1 received
+        let mut buf = to_server("hello");
+        assert!(client.encrypt(&mut buf)?.is_some());
+        expect_data(server.decrypt(buf, 1)?);
+        let mut in_flight_to_server = Vec::new();
+        for _ in 0..2 {
+            let mut buf = to_server("hello");
+            assert!(client.encrypt(&mut buf)?.is_some());
+            in_flight_to_server.push(buf);
+        }
+        assert!(client.maintain()?.is_none());
+        let mut in_flight_to_client = to_client("world");
+        assert!(server.encrypt(&mut in_flight_to_client)?.is_some());
+        expect_data(client.decrypt(in_flight_to_client.clone(), ())?);
+
+        let mut in_flight_to_client = to_client("world");
+        assert!(server.encrypt(&mut in_flight_to_client)?.is_some());
+
+        // rekey
+        establish(&client, &server, 1)?;
+
+        // packets encrypted with the old keys are still accepted
+        for buf in in_flight_to_server {
+            assert_eq!(expect_data(server.decrypt(buf, 1)?), to_server("hello"));
+        }
+        assert_eq!(expect_data(client.decrypt(in_flight_to_client, ())?), to_client("world"));
+
+        // new keys are used
+        let mut buf = to_server("hello");
+        assert!(client.encrypt(&mut buf)?.is_some());
+        assert_eq!(expect_data(server.decrypt(buf, 1)?), to_server("hello"));
+        assert!(client.maintain()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_drops_old_keys_after_grace_period() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let options = || SessionOptions {
+            rekey_after_time: Duration::ZERO,
+            rekey_grace_period: Duration::ZERO,
+            ..Default::default()
+        };
+        let client: Client = new_manager(&keys, Role::Client, options());
+        let server: Server = new_manager(&keys, Role::Server, options());
+        establish(&client, &server, 1)?;
+
+        let mut old = to_server("hello");
+        assert!(client.encrypt(&mut old)?.is_some());
+
+        establish(&client, &server, 1)?;
+        assert!(server.maintain()?.is_none());
+        assert!(server.decrypt(old, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_peers() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("kissvpn-session-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("peers");
+        std::fs::write(&path, "alice allowed-ips=10.9.0.2 key0\nbob allowed-ips=10.9.0.3 key1\n")?;
+        let server: Server = SessionManager::new(Peers::load(&path)?, Role::Server, SessionOptions::default());
+
+        let alice: Client = new_manager(&Keys::derive("key0"), Role::Client, SessionOptions::default());
+        let bob: Client = new_manager(&Keys::derive("key1"), Role::Client, SessionOptions::default());
+        establish(&alice, &server, 1)?;
+        establish(&bob, &server, 2)?;
+
+        let alice_ip = [10, 9, 0, 2];
+        let bob_ip = [10, 9, 0, 3];
+        for (client, ip) in [(&alice, alice_ip), (&bob, bob_ip)] {
+            let mut buf = ip_packet(ip, SERVER_IP, "hello");
+            client.encrypt(&mut buf)?;
+            expect_data(server.decrypt(buf, 0)?);  // even from an unexpected address
+        }
+
+        // routed by destination
+        for (client, ip, addr) in [(&alice, alice_ip, 0), (&bob, bob_ip, 0)] {
+            let mut buf = ip_packet(SERVER_IP, ip, "world");
+            assert_eq!(server.encrypt(&mut buf)?, Some(addr));
+            assert_eq!(expect_data(client.decrypt(buf, ())?), ip_packet(SERVER_IP, ip, "world"));
+        }
+        let mut buf = ip_packet(SERVER_IP, alice_ip, "world");
+        server.encrypt(&mut buf)?;
+        assert!(bob.decrypt(buf, ()).is_err());
+        assert!(server.encrypt(&mut ip_packet(SERVER_IP, [10, 9, 0, 4], "world"))?.is_none());
+
+        // spoofed source addresses are dropped
+        for src in [bob_ip, [10, 9, 0, 4]] {
+            let mut buf = ip_packet(src, SERVER_IP, "hello");
+            alice.encrypt(&mut buf)?;
+            assert!(server.decrypt(buf, 0).is_err());
+        }
+
+        let eve: Client = new_manager(&Keys::derive("key2"), Role::Client, SessionOptions::default());
+        assert!(server.decrypt(eve.maintain()?.unwrap(), 3).is_err());
+
+        // revoke bob
+        std::fs::write(&path, "alice allowed-ips=10.9.0.2 key0\n")?;
+        std::fs::File::options().write(true).open(&path)?
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(1))?;
+        assert!(server.maintain()?.is_none());
+        let mut buf = ip_packet(bob_ip, SERVER_IP, "hello");
+        assert!(bob.encrypt(&mut buf)?.is_some());
+        assert!(server.decrypt(buf, 2).is_err());
+        assert!(server.encrypt(&mut ip_packet(SERVER_IP, bob_ip, "world"))?.is_none());
+        let bob: Client = new_manager(&Keys::derive("key1"), Role::Client, SessionOptions::default());
+        assert!(server.decrypt(bob.maintain()?.unwrap(), 2).is_err());
+
+        // alice is not affected
+        let mut buf = ip_packet(SERVER_IP, alice_ip, "world");
+        assert!(server.encrypt(&mut buf)?.is_some());
+        expect_data(alice.decrypt(buf, ())?);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_config() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let push = PushConfig::new(&["10.9.0.0/24".parse()?], vec!["192.168.0.0/16".parse()?],
+                                   vec!["10.9.0.1".parse()?], 1340)?;
+        let server: Server = new_manager(&keys, Role::Server, SessionOptions {
+            push: Some(Arc::new(push)),
+            ..Default::default()
+        });
+        let client_options = || SessionOptions { rekey_after_packets: 2, ..Default::default() };
+        let client: Client = new_manager(&keys, Role::Client, client_options());
+
+        let response = expect_control(server.decrypt(client.maintain()?.unwrap(), 1)?).unwrap();
+        let Received::Control { reply: Some(keepalive), config: Some(config) } = client.decrypt(response, ())? else {
+            panic!("expected tun config");
+        };
+        assert_eq!(config.routes, ["192.168.0.0/16".parse()?]);
+        assert_eq!(config.dns, ["10.9.0.1".parse::<IpAddr>()?]);
+        assert_eq!(config.mtu, Some(1340));
+        expect_data(server.decrypt(keepalive, 1)?);
+
+        // routed to the assigned address before the client sends anything
+        let [address] = config.addresses[..] else {
+            panic!("expected one address");
+        };
+        let IpAddr::V4(ip) = address.addr else {
+            panic!("expected IPv4 address");
+        };
+        let mut buf = ip_packet(SERVER_IP, ip.octets(), "hello");
+        assert_eq!(server.encrypt(&mut buf)?, Some(1));
+        expect_data(client.decrypt(buf, ())?);
+
+        // same address for a new handshake of the same instance
+        let mut buf = to_server("hello");
+        client.encrypt(&mut buf)?;
+        let response = expect_control(server.decrypt(client.maintain()?.unwrap(), 1)?).unwrap();
+        let Received::Control { reply: Some(keepalive), config: Some(new_config) } = client.decrypt(response, ())? else {
+            panic!("expected tun config");
+        };
+        assert_eq!(new_config, config);
+        expect_data(server.decrypt(keepalive, 1)?);
+
+        // another instance sharing the key gets its own address, without replacing the session of the first
+        let other_client: Client = new_manager(&keys, Role::Client, client_options());
+        let response = expect_control(server.decrypt(other_client.maintain()?.unwrap(), 2)?).unwrap();
+        let Received::Control { reply: Some(keepalive), config: Some(other_config) } = other_client.decrypt(response, ())? else {
+            panic!("expected tun config");
+        };
+        expect_data(server.decrypt(keepalive, 2)?);
+        let [other_address] = other_config.addresses[..] else {
+            panic!("expected one address");
+        };
+        assert_ne!(other_address.addr, address.addr);
+        let IpAddr::V4(other_ip) = other_address.addr else {
+            panic!("expected IPv4 address");
+        };
+        let mut buf = ip_packet(SERVER_IP, ip.octets(), "hello");
+        assert_eq!(server.encrypt(&mut buf)?, Some(1));
+        expect_data(client.decrypt(buf, ())?);
+        let mut buf = ip_packet(SERVER_IP, other_ip.octets(), "hello");
+        assert_eq!(server.encrypt(&mut buf)?, Some(2));
+        expect_data(other_client.decrypt(buf, ())?);
+
+        // an instance can't use the address of the other
+        let mut buf = ip_packet(ip.octets(), SERVER_IP, "hello");
+        other_client.encrypt(&mut buf)?;
+        assert!(server.decrypt(buf, 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tap() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let options = || SessionOptions { tap: true, ..Default::default() };
+        let server: Server = new_manager(&keys, Role::Server, options());
+        let alice: Client = new_manager(&keys, Role::Client, options());
+        establish(&alice, &server, 1)?;
+
+        let server_mac = [0x02, 0, 0, 0, 0, 1];
+        let alice_mac = [0x02, 0, 0, 0, 0, 2];
+
+        // unknown yet, flooded
+        assert!(server.should_flood(&frame(alice_mac, server_mac)));
+        let flooded = server.encrypt_flood(&frame(alice_mac, server_mac))?;
+        assert_eq!(flooded.len(), 1);
+        assert_eq!(flooded[0].1, 1);
+        assert_eq!(expect_data(alice.decrypt(flooded[0].0.clone(), ())?), frame(alice_mac, server_mac));
+
+        // any frame is accepted, and the source is learned
+        let mut buf = frame([0xff; 6], alice_mac);
+        assert!(!alice.should_flood(&buf));
+        alice.encrypt(&mut buf)?;
+        assert_eq!(expect_data(server.decrypt(buf, 1)?), frame([0xff; 6], alice_mac));
+        assert!(!server.should_flood(&frame(alice_mac, server_mac)));
+        let mut buf = frame(alice_mac, server_mac);
+        assert_eq!(server.encrypt(&mut buf)?, Some(1));
+        expect_data(alice.decrypt(buf, ())?);
+
+        assert!(server.should_flood(&frame([0xff; 6], server_mac)));
+        assert!(server.should_flood(&frame([0x01, 0, 0x5e, 0, 0, 1], server_mac)));
+
+        // frames too short for an ethernet header are dropped
+        let mut buf = BytesMut::from(&alice_mac[..]);
+        alice.encrypt(&mut buf)?;
+        assert!(server.decrypt(buf, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tap_mac_owners() -> Result<()> {
+        let keys = Keys::derive("key0");
+        let options = || SessionOptions { tap: true, ..Default::default() };
+        let server: Server = new_manager(&keys, Role::Server, options());
+        let alice: Client = new_manager(&keys, Role::Client, options());
+        let bob: Client = new_manager(&keys, Role::Client, options());
+        establish(&alice, &server, 1)?;
+        establish(&bob, &server, 2)?;
+
+        let server_mac = [0x02, 0, 0, 0, 0, 1];
+        let alice_mac = [0x02, 0, 0, 0, 0, 2];
+
+        // learned from the frames the server sends
+        server.encrypt_flood(&frame([0xff; 6], server_mac))?;
+        let mut buf = frame([0xff; 6], alice_mac);
+        alice.encrypt(&mut buf)?;
+        expect_data(server.decrypt(buf, 1)?);
+
+        // other peers can't take them
+        for mac in [server_mac, alice_mac] {
+            let mut buf = frame([0xff; 6], mac);
+            bob.encrypt(&mut buf)?;
+            assert!(server.decrypt(buf, 2).is_err());
+        }
+        let mut buf = frame(alice_mac, server_mac);
+        assert_eq!(server.encrypt(&mut buf)?, Some(1));
+        // not sent to any peer
+        assert_eq!(server.encrypt(&mut frame(server_mac, alice_mac))?, None);
+
+        // limited number of addresses per peer, frames from further ones are still delivered
+        for i in 0..MAX_MACS_PER_OWNER as u16 + 1 {
+            let [x, y] = i.to_be_bytes();
+            let mut buf = frame([0xff; 6], [0x02, 1, 0, 0, x, y]);
+            bob.encrypt(&mut buf)?;
+            expect_data(server.decrypt(buf, 2)?);
+        }
+        assert!(!server.should_flood(&frame([0x02, 1, 0, 0, 0, 0], server_mac)));
+        let [x, y] = (MAX_MACS_PER_OWNER as u16).to_be_bytes();
+        assert!(server.should_flood(&frame([0x02, 1, 0, 0, x, y], server_mac)));
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use aead::Buffer;
+use aead::generic_array::GenericArray;
+use aes_gcm::Aes256Gcm;
+use anyhow::Result;
+use chacha20poly1305::{ChaCha20Poly1305, ChaCha8Poly1305, XChaCha20Poly1305, KeyInit, AeadInPlace};
+
+/// AEAD algorithm used for encrypting packets.
+/// Both ends must use the same suite, the client sends its id in the handshake.
+/// All suites use 32 bytes keys and 16 bytes tags.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Suite {
+    #[default]
+    ChaCha20Poly1305,
+    XChaCha20Poly1305,
+    Aes256Gcm,
+    /// Reduced round ChaCha, the original kissvpn cipher. Fast, but not recommended.
+    ChaCha8Poly1305,
+}
+
+pub const TAG_SIZE: usize = 16;
+pub const MAX_NONCE_SIZE: usize = 24;
+
+pub const ALL_SUITES: &[Suite] = &[
+    Suite::ChaCha20Poly1305,
+    Suite::XChaCha20Poly1305,
+    Suite::Aes256Gcm,
+    Suite::ChaCha8Poly1305,
+];
+
+impl Suite {
+    /// Identifier on the wire
+    pub fn id(self) -> u8 {
+        match self {
+            Suite::ChaCha20Poly1305 => 1,
+            Suite::XChaCha20Poly1305 => 2,
+            Suite::Aes256Gcm => 3,
+            Suite::ChaCha8Poly1305 => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Suite> {
+        ALL_SUITES.iter().copied().find(|x| x.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Suite::ChaCha20Poly1305 => "chacha20-poly1305",
+            Suite::XChaCha20Poly1305 => "xchacha20-poly1305",
+            Suite::Aes256Gcm => "aes-256-gcm",
+            Suite::ChaCha8Poly1305 => "chacha8-poly1305",
+        }
+    }
+
+    pub fn nonce_size(self) -> usize {
+        match self {
+            Suite::XChaCha20Poly1305 => 24,
+            _ => 12,
+        }
+    }
+}
+
+impl std::fmt::Display for Suite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Suite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ALL_SUITES.iter().copied().find(|x| x.name() == s)
+            .ok_or(anyhow::format_err!("Unknown cipher suite {}, available: {}", s,
+                                       ALL_SUITES.iter().map(|x| x.name()).collect::<Vec<_>>().join(", ")))
+    }
+}
+
+/// AEAD instance of some suite, initialized with a key
+#[derive(Clone)]
+pub enum Aead {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha8Poly1305(ChaCha8Poly1305),
+}
+
+impl Aead {
+    pub fn new(suite: Suite, key: &[u8]) -> Aead {
+        let key = GenericArray::from_slice(key);
+        match suite {
+            Suite::ChaCha20Poly1305 => Aead::ChaCha20Poly1305(ChaCha20Poly1305::new(key)),
+            Suite::XChaCha20Poly1305 => Aead::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+            Suite::Aes256Gcm => Aead::Aes256Gcm(Box::new(Aes256Gcm::new(key))),
+            Suite::ChaCha8Poly1305 => Aead::ChaCha8Poly1305(ChaCha8Poly1305::new(key)),
+        }
+    }
+
+    /// Encrypt in-place and append the tag. Length of nonce must match the suite.
+    pub fn encrypt(&self, nonce: &[u8], aad: &[u8], buf: &mut impl Buffer) -> aead::Result<()> {
+        match self {
+            Aead::ChaCha20Poly1305(x) => x.encrypt_in_place(GenericArray::from_slice(nonce), aad, buf),
+            Aead::XChaCha20Poly1305(x) => x.encrypt_in_place(GenericArray::from_slice(nonce), aad, buf),
+            Aead::Aes256Gcm(x) => x.encrypt_in_place(GenericArray::from_slice(nonce), aad, buf),
+            Aead::ChaCha8Poly1305(x) => x.encrypt_in_place(GenericArray::from_slice(nonce), aad, buf),
+        }
+    }
+
+    /// Verify the tag and decrypt in-place. The buffer is not modified on failure.
+    pub fn decrypt(&self, nonce: &[u8], aad: &[u8], buf: &mut [u8], tag: &[u8]) -> aead::Result<()> {
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            Aead::ChaCha20Poly1305(x) => x.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag),
+            Aead::XChaCha20Poly1305(x) => x.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag),
+            Aead::Aes256Gcm(x) => x.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag),
+            Aead::ChaCha8Poly1305(x) => x.decrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf, tag),
+        }
+    }
+}
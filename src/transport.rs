@@ -1,23 +1,54 @@
+use std::os::fd::BorrowedFd;
+
 use bytes::{Buf, BytesMut};
 use anyhow::Result;
 
 pub trait Transport: Sync {
+    // Address of a peer, as returned by receive() and accepted by send().
+    // For client-side transports, it's one of the server's addresses (e.g. IPv6 or IPv4).
+    // The caller decides which address to trust (e.g. where crypto verified packets come from).
+    type Addr: Clone + PartialEq + std::fmt::Debug + Send + Sync;
+
     // Following methods are all using `&self` instead of `&mut self`,
     // so that they can be used separately in sending and receiving loop
 
-    fn send(&self, buf: impl Buf) -> Result<()>;
-    fn receive(&self) -> Result<BytesMut>;
+    fn send(&self, buf: impl Buf, addr: &Self::Addr) -> Result<()>;
+    // Returns an io error of kind WouldBlock or TimedOut if nothing is received within constants::POLL_INTERVAL
+    fn receive(&self) -> Result<(BytesMut, Self::Addr)>;
+
+    // Batched versions, to save syscalls (e.g. sendmmsg / recvmmsg).
+    // send_batch tries all packets and returns the first error.
+    // receive_batch waits like receive() for the first packet, then returns up to `max` already received.
+    fn send_batch(&self, bufs: &[(BytesMut, Self::Addr)]) -> Result<()> {
+        let mut first_error = None;
+        for (buf, addr) in bufs {
+            if let Err(e) = self.send(&buf[..], addr) {
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+    fn receive_batch(&self, _max: usize) -> Result<Vec<(BytesMut, Self::Addr)>> {
+        Ok(vec![self.receive()?])
+    }
 
     fn needs_keepalive(&self) -> bool;
 
-    // The caller must call this if last received packet is crypto verified,
-    // so that the transport knows the peer is trusted.
-    // Usually, for a server-side transport, ready_to_send() only returns true after this.
-    fn mark_last_received_valid(&self) {}
+    // Addresses to send to before receiving anything, i.e. the server for client-side transports,
+    // preferred first. Server-side transports only send to addresses they received from.
+    fn default_addrs(&self) -> Vec<Self::Addr> { Vec::new() }
+
+    // For the event loop engine: switch receive() to return WouldBlock immediately when nothing was received,
+    // and return a fd that becomes readable when there's something. None if not supported.
+    fn nonblocking_fd(&self) -> Result<Option<BorrowedFd<'_>>> { Ok(None) }
+
+    // The caller must call this when a packet received from `addr` is crypto verified,
+    // so that the transport knows the peer is trusted (e.g. to echo what it sent).
+    fn mark_received_valid(&self, _addr: &Self::Addr) {}
 
-    // Return true if this transport is ready for sending.
-    // Mostly useful for server side, because it's only ready after receiving from client first.
-    fn ready_to_send(&self) -> bool { true }
+    // Called once when the engine stops, after the last send and receive,
+    // e.g. to reset connections of stateful transports
+    fn shutdown(&self) {}
 }
 
 
@@ -1,4 +1,8 @@
-use std::{net::ToSocketAddrs, sync::atomic};
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::BorrowedFd;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use bytes::{Buf, BufMut, BytesMut};
 use anyhow::Result;
@@ -165,26 +169,54 @@ impl FakednsClientTransport {
 }
 
 impl Transport for FakednsClientTransport {
+    type Addr = SocketAddr;
+
     fn needs_keepalive(&self) -> bool { self.udp_transport.needs_keepalive() }
 
-    fn send(&self, buf: impl Buf) -> Result<()> {
+    fn default_addrs(&self) -> Vec<SocketAddr> { self.udp_transport.default_addrs() }
+
+    fn nonblocking_fd(&self) -> Result<Option<BorrowedFd<'_>>> { self.udp_transport.nonblocking_fd() }
+
+    fn send(&self, buf: impl Buf, addr: &SocketAddr) -> Result<()> {
         let query_id = rand::thread_rng().next_u32() as u16;
         let encoded = encode_to_query(buf, query_id);
-        self.udp_transport.send(encoded)?;
+        self.udp_transport.send(encoded, addr)?;
         Ok(())
     }
 
-    fn receive(&self) -> Result<BytesMut> {
-        let buf = self.udp_transport.receive()?;
-        decode_from_response(buf)
+    fn receive(&self) -> Result<(BytesMut, SocketAddr)> {
+        let (buf, addr) = self.udp_transport.receive()?;
+        Ok((decode_from_response(buf)?, addr))
+    }
+
+    fn send_batch(&self, bufs: &[(BytesMut, SocketAddr)]) -> Result<()> {
+        let encoded: Vec<(BytesMut, SocketAddr)> = bufs.iter()
+            .map(|(buf, addr)| (encode_to_query(&buf[..], rand::thread_rng().next_u32() as u16), *addr))
+            .collect();
+        self.udp_transport.send_batch(&encoded)
+    }
+
+    // malformed packets are dropped
+    fn receive_batch(&self, max: usize) -> Result<Vec<(BytesMut, SocketAddr)>> {
+        Ok(self.udp_transport.receive_batch(max)?.into_iter()
+           .filter_map(|(buf, addr)| Some((decode_from_response(buf).ok()?, addr)))
+           .collect())
     }
 }
 
 
+// Responses reuse the id of the last authenticated query from the same address
+// (so that spoofed queries can't change it).
+// Anyone can send queries, so when a table grows too large, the addresses least recently queried from
+// are forgotten (a quarter at once, to amortize); clients keep querying, so their ids stay.
+const MAX_QUERY_IDS: usize = 4096;
+
 pub struct FakednsServerTransport {
     udp_transport: UdpServerTransport,
-    query_id: atomic::AtomicU16,
-    last_query_id: atomic::AtomicU16,
+    /// Address -> (id, time of the query), of authenticated queries
+    query_ids: Mutex<HashMap<SocketAddr, (u16, Instant)>>,
+    /// Same for the last query received from each address, until it's authenticated
+    pending_query_ids: Mutex<HashMap<SocketAddr, (u16, Instant)>>,
 }
 
 impl FakednsServerTransport {
@@ -192,37 +224,87 @@ impl FakednsServerTransport {
     where T: ToSocketAddrs {
         Ok(FakednsServerTransport {
             udp_transport: UdpServerTransport::create(local_addr)?,
-            query_id: atomic::AtomicU16::new(0),
-            last_query_id: atomic::AtomicU16::new(0),
+            query_ids: Mutex::new(HashMap::new()),
+            pending_query_ids: Mutex::new(HashMap::new()),
         })
     }
 }
 
+impl FakednsServerTransport {
+    /// Decode a query, and remember its id for responses once it's authenticated
+    fn decode(&self, buf: BytesMut, addr: SocketAddr) -> Result<(BytesMut, SocketAddr)> {
+        let (decoded, decoded_query_id) = decode_from_query(buf)?;
+        insert_query_id(&mut self.pending_query_ids.lock().unwrap(), addr, (decoded_query_id, Instant::now()));
+        Ok((decoded, addr))
+    }
+
+    fn query_id(query_ids: &HashMap<SocketAddr, (u16, Instant)>, addr: &SocketAddr) -> u16 {
+        query_ids.get(addr).map(|(id, _)| *id)
+            .unwrap_or_else(|| rand::thread_rng().next_u32() as u16)
+    }
+}
+
+fn insert_query_id(query_ids: &mut HashMap<SocketAddr, (u16, Instant)>, addr: SocketAddr, entry: (u16, Instant)) {
+    if query_ids.len() >= MAX_QUERY_IDS && !query_ids.contains_key(&addr) {
+        evict_oldest(query_ids, MAX_QUERY_IDS / 4);
+    }
+    query_ids.insert(addr, entry);
+}
+
+/// Remove (at least) the `n` entries with the oldest queries
+fn evict_oldest(query_ids: &mut HashMap<SocketAddr, (u16, Instant)>, n: usize) {
+    let mut times: Vec<Instant> = query_ids.values().map(|(_, t)| *t).collect();
+    if n == 0 || times.is_empty() {
+        return;
+    }
+    let n = n.min(times.len());
+    let (_, &mut cutoff, _) = times.select_nth_unstable(n - 1);
+    query_ids.retain(|_, (_, t)| *t > cutoff);
+}
+
 impl Transport for FakednsServerTransport {
+    type Addr = SocketAddr;
+
     fn needs_keepalive(&self) -> bool { self.udp_transport.needs_keepalive() }
 
-    fn send(&self, buf: impl Buf) -> Result<()> {
-        let encoded = encode_to_response(buf, self.query_id.load(atomic::Ordering::Acquire));
-        self.udp_transport.send(encoded)?;
+    fn nonblocking_fd(&self) -> Result<Option<BorrowedFd<'_>>> { self.udp_transport.nonblocking_fd() }
+
+    fn mark_received_valid(&self, addr: &SocketAddr) {
+        let Some(entry) = self.pending_query_ids.lock().unwrap().remove(addr) else {
+            return;
+        };
+        insert_query_id(&mut self.query_ids.lock().unwrap(), *addr, entry);
+    }
+
+    fn send(&self, buf: impl Buf, addr: &SocketAddr) -> Result<()> {
+        let query_id = Self::query_id(&self.query_ids.lock().unwrap(), addr);
+        let encoded = encode_to_response(buf, query_id);
+        self.udp_transport.send(encoded, addr)?;
         Ok(())
     }
 
-    fn receive(&self) -> Result<BytesMut> {
-        let buf = self.udp_transport.receive()?;
-        let (decoded, decoded_query_id) = decode_from_query(buf)?;
-        self.last_query_id.store(decoded_query_id, atomic::Ordering::Release);
-        Ok(decoded)
+    fn receive(&self) -> Result<(BytesMut, SocketAddr)> {
+        let (buf, addr) = self.udp_transport.receive()?;
+        self.decode(buf, addr)
     }
 
-    fn mark_last_received_valid(&self) {
-        self.query_id.store(
-            self.last_query_id.load(atomic::Ordering::Acquire),
-            atomic::Ordering::Release);
-        self.udp_transport.mark_last_received_valid();
+    fn send_batch(&self, bufs: &[(BytesMut, SocketAddr)]) -> Result<()> {
+        let encoded: Vec<(BytesMut, SocketAddr)> = {
+            let query_ids = self.query_ids.lock().unwrap();
+            bufs.iter()
+                .map(|(buf, addr)| {
+                    (encode_to_response(&buf[..], Self::query_id(&query_ids, addr)), *addr)
+                })
+                .collect()
+        };
+        self.udp_transport.send_batch(&encoded)
     }
 
-    fn ready_to_send(&self) -> bool {
-        self.udp_transport.ready_to_send()
+    // malformed packets are dropped
+    fn receive_batch(&self, max: usize) -> Result<Vec<(BytesMut, SocketAddr)>> {
+        Ok(self.udp_transport.receive_batch(max)?.into_iter()
+           .filter_map(|(buf, addr)| self.decode(buf, addr).ok())
+           .collect())
     }
 }
 
@@ -268,4 +350,33 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_evict_oldest() {
+        let start = Instant::now();
+        let mut query_ids: HashMap<SocketAddr, (u16, Instant)> = (0..8u16)
+            .map(|i| (SocketAddr::from(([127, 0, 0, 1], i)), (i, start + std::time::Duration::from_secs(i.into()))))
+            .collect();
+        evict_oldest(&mut query_ids, 3);
+        let mut ports: Vec<u16> = query_ids.keys().map(|x| x.port()).collect();
+        ports.sort();
+        assert_eq!(ports, [3, 4, 5, 6, 7]);
+        evict_oldest(&mut query_ids, 10);
+        assert!(query_ids.is_empty());
+    }
+
+    #[test]
+    fn test_query_id_after_valid() -> Result<()> {
+        let server = FakednsServerTransport::create("127.0.0.1:9994")?;
+        let client = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        client.send_to(&encode_to_query(&b"hello"[..], 1234), "127.0.0.1:9994")?;
+        let (buf, addr) = server.receive()?;
+        assert_eq!(&buf[..], b"hello");
+        // only used for responses once the payload is authenticated
+        assert!(server.query_ids.lock().unwrap().is_empty());
+        server.mark_received_valid(&addr);
+        assert_eq!(server.query_ids.lock().unwrap().get(&addr).map(|(id, _)| *id), Some(1234));
+        assert!(server.pending_query_ids.lock().unwrap().is_empty());
+        Ok(())
+    }
 }
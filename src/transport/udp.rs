@@ -1,10 +1,13 @@
-use core::slice;
 use std::collections::{btree_map, BTreeMap};
+use std::io::{IoSlice, IoSliceMut};
 use std::time;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::{ops::Deref, sync::{Arc, Mutex}};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-use crate::constants::BUF_CAPACITY;
+use crate::constants::{BUF_CAPACITY, FWMARK, POLL_INTERVAL};
+use crate::tun;
 
 use super::Transport;
 
@@ -13,6 +16,100 @@ use log::{info, trace, warn};
 use bytes::{Buf, BytesMut};
 use rand::RngCore;
 use nix::sys::epoll::{Epoll, EpollEvent, EpollFlags, EpollTimeout};
+use nix::sys::socket::{self, setsockopt, sockopt, MsgFlags, MultiHeaders, SockaddrStorage};
+
+// Batched socket I/O, with one syscall for multiple packets
+
+/// Socket buffers for bursts of batches at high rates, above the default net.core.[rw]mem_max
+const SOCKET_BUFFER_SIZE: usize = 4 << 20;
+
+/// The forced version needs CAP_NET_ADMIN, otherwise it's capped by net.core.[rw]mem_max
+fn set_buffer_sizes(sock: &UdpSocket) {
+    if setsockopt(sock, sockopt::RcvBufForce, &SOCKET_BUFFER_SIZE)
+        .and_then(|_| setsockopt(sock, sockopt::SndBufForce, &SOCKET_BUFFER_SIZE)).is_err() {
+        let _ = setsockopt(sock, sockopt::RcvBuf, &SOCKET_BUFFER_SIZE);
+        let _ = setsockopt(sock, sockopt::SndBuf, &SOCKET_BUFFER_SIZE);
+    }
+}
+
+fn to_socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    match (addr.as_sockaddr_in(), addr.as_sockaddr_in6()) {
+        (Some(v4), _) => Some(SocketAddr::V4((*v4).into())),
+        (_, Some(v6)) => Some(SocketAddr::V6((*v6).into())),
+        _ => None,
+    }
+}
+
+/// Receive buffers not filled by a recvmmsg, for the next ones: most batches are far from full
+#[derive(Default)]
+struct SpareBuffers(Mutex<Vec<BytesMut>>);
+
+impl SpareBuffers {
+    /// `n` buffers of BUF_CAPACITY bytes
+    fn take(&self, n: usize) -> Vec<BytesMut> {
+        let mut bufs = {
+            let mut spare = self.0.lock().unwrap();
+            let len = spare.len();
+            spare.split_off(len.saturating_sub(n))
+        };
+        bufs.resize_with(n, || BytesMut::zeroed(BUF_CAPACITY));
+        bufs
+    }
+
+    fn put_back(&self, bufs: impl Iterator<Item = BytesMut>) {
+        self.0.lock().unwrap().extend(bufs);
+    }
+}
+
+/// recvmmsg up to `max` packets: unless `dontwait`, wait for the first one (within the socket's read timeout),
+/// then take those already queued. Addresses are the sources, None if unknown.
+fn recv_batch(sock: &UdpSocket, max: usize, dontwait: bool, spare: &SpareBuffers)
+              -> std::io::Result<Vec<(BytesMut, Option<SocketAddr>)>> {
+    let mut bufs = spare.take(max);
+    let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(max, None);
+    let flags = if dontwait { MsgFlags::MSG_DONTWAIT } else { MsgFlags::MSG_WAITFORONE };
+    let received: nix::Result<Vec<(usize, Option<SocketAddr>)>> = {
+        let mut iovs: Vec<[IoSliceMut; 1]> = bufs.iter_mut().map(|x| [IoSliceMut::new(x)]).collect();
+        socket::recvmmsg(sock.as_raw_fd(), &mut headers, iovs.iter_mut(), flags, None)
+            .map(|results| results.map(|x| (x.bytes, x.address.as_ref().and_then(to_socket_addr))).collect())
+    };
+    let received = match received {
+        Ok(x) => x,
+        Err(e) => {
+            spare.put_back(bufs.into_iter());
+            return Err(e.into());
+        },
+    };
+    let mut bufs = bufs.into_iter();
+    let packets = bufs.by_ref().zip(received)
+        .map(|(mut buf, (len, addr))| {
+            buf.truncate(len);
+            (buf, addr)
+        })
+        .collect();
+    spare.put_back(bufs);
+    Ok(packets)
+}
+
+/// sendmmsg, to `addrs` (None for the connected address).
+/// All packets are tried, the first error is returned.
+fn send_batch_to(sock: &UdpSocket, bufs: &[&[u8]], addrs: &[Option<SockaddrStorage>]) -> std::io::Result<()> {
+    let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(bufs.len(), None);
+    let mut first_error = None;
+    let mut sent = 0;
+    while sent < bufs.len() {
+        let iovs: Vec<[IoSlice; 1]> = bufs[sent..].iter().map(|x| [IoSlice::new(x)]).collect();
+        match socket::sendmmsg(sock.as_raw_fd(), &mut headers, &iovs, &addrs[sent..], [], MsgFlags::empty()) {
+            Ok(results) => sent += results.count(),
+            Err(e) => {
+                // the first packet failed, skip it
+                first_error.get_or_insert(e);
+                sent += 1;
+            },
+        }
+    }
+    first_error.map_or(Ok(()), |e| Err(e.into()))
+}
 
 pub struct UdpClientTransportOptions {
     /// Max number of sockets at each timepoint
@@ -33,33 +130,55 @@ impl Default for UdpClientTransportOptions {
     }
 }
 
+/// Sockets with packets handled by one receive_batch()
+const MAX_EPOLL_EVENTS: usize = 16;
+
 struct SockContext {
     sock: Arc<UdpSocket>,
+    remote_addr: SocketAddr,
     created: time::Instant,
 }
 
+/// If the remote name resolves to both IPv6 and IPv4 addresses, the first of each family are used
+/// ("happy eyeballs"): handshakes are sent to both, IPv6 first, and the session continues with
+/// the address the response came from (see SessionManager). The address is the `Addr` of this transport.
 pub struct UdpClientTransport {
-    remote_addr: SocketAddr,
+    remote_addrs: Vec<SocketAddr>,
     options: UdpClientTransportOptions,
     sock_ctxs: Mutex<BTreeMap<u64, SockContext>>,
     epoll: Epoll,
+    /// receive() doesn't wait, see Transport::nonblocking_fd
+    nonblocking: AtomicBool,
+    spare_bufs: SpareBuffers,
+}
+
+/// First IPv6 and first IPv4 address, in this order
+fn select_remote_addrs(addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let addrs: Vec<SocketAddr> = addrs.collect();
+    [SocketAddr::is_ipv6, SocketAddr::is_ipv4].iter()
+        .filter_map(|is_family| addrs.iter().find(|x| is_family(x)).copied())
+        .collect()
 }
 
 impl UdpClientTransport {
     pub fn create<TR>(remote_addr: TR, options: UdpClientTransportOptions) -> Result<UdpClientTransport>
     where TR: ToSocketAddrs {
-        let remote_addr = remote_addr.to_socket_addrs()?
-            .next().ok_or(anyhow::format_err!("lookup_host failed"))?;
-        info!("Creating udp client transport to {remote_addr}");
+        let remote_addrs = select_remote_addrs(remote_addr.to_socket_addrs()?);
+        if remote_addrs.is_empty() {
+            anyhow::bail!("lookup_host failed");
+        }
+        info!("Creating udp client transport to {:?}", remote_addrs);
         Ok(UdpClientTransport {
-            remote_addr,
+            remote_addrs,
             options,
             sock_ctxs: Mutex::new(BTreeMap::new()),
             epoll: Epoll::new(nix::sys::epoll::EpollCreateFlags::empty())?,
+            nonblocking: AtomicBool::new(false),
+            spare_bufs: SpareBuffers::default(),
         })
     }
 
-    fn get_or_create_socket_for_sending(&self) -> Result<(u64, Arc<UdpSocket>)> {
+    fn get_or_create_socket_for_sending(&self, remote_addr: SocketAddr) -> Result<(u64, Arc<UdpSocket>)> {
         let mut sock_ctxs = self.sock_ctxs.lock().unwrap();
 
         // clear outdated sockets
@@ -78,7 +197,7 @@ impl UdpClientTransport {
         let available_socks: Vec<(u64, Arc<UdpSocket>)> =
             sock_ctxs.iter()
             .filter_map(|(id, x)| {
-                if x.created >= now - self.options.socket_send_duration {
+                if x.remote_addr == remote_addr && x.created >= now - self.options.socket_send_duration {
                     Some((id.clone(), x.sock.clone()))
                 } else {
                     None
@@ -89,15 +208,24 @@ impl UdpClientTransport {
         // create new socket if required
         if available_socks.len() < self.options.max_send_sockets {
             trace!("Creating new udp socket");
-            let sock = UdpSocket::bind("0.0.0.0:0")?;
+            let sock = UdpSocket::bind(if remote_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
             // read timeout should not happen because we use epoll, just in case
             sock.set_read_timeout(Some(std::time::Duration::from_millis(1)))?;
-            sock.connect(self.remote_addr)?;
+            // so that it bypasses a default route through the tunnel, needs CAP_NET_ADMIN
+            if let Err(e) = setsockopt(&sock, sockopt::Mark, &FWMARK) {
+                if tun::policy_routing_active() {
+                    anyhow::bail!("Cannot set fwmark on udp socket, its packets would loop into the tunnel: {}", e);
+                }
+                trace!("Cannot set fwmark on udp socket: {}", e);
+            }
+            set_buffer_sizes(&sock);
+            sock.connect(remote_addr)?;
 
             let sock = Arc::new(sock);
             let sock_id = sock_ctxs.last_entry().map_or(0, |e| e.key() + 1);
             sock_ctxs.insert(sock_id, SockContext {
                 sock: sock.clone(),
+                remote_addr,
                 created: now,
             });
 
@@ -111,9 +239,20 @@ impl UdpClientTransport {
         return Ok(available_socks[rand_id].clone());
     }
 
-    fn get_socket_by_id(&self, id: u64) -> Option<Arc<UdpSocket>> {
+    /// Drop the socket on errors other than connection refused (server not started)
+    fn check_send_result(&self, sock_id: u64, result: std::io::Result<()>) -> Result<()> {
+        if let Err(e) = &result {
+            if e.kind() != std::io::ErrorKind::ConnectionRefused {
+                warn!("Udp send error: {}", e);
+                self.remove_socket_by_id(sock_id);
+            }
+        }
+        Ok(result?)
+    }
+
+    fn get_socket_by_id(&self, id: u64) -> Option<(Arc<UdpSocket>, SocketAddr)> {
         let sock_ctxs = self.sock_ctxs.lock().unwrap();
-        sock_ctxs.get(&id).map(|x| x.sock.clone())
+        sock_ctxs.get(&id).map(|x| (x.sock.clone(), x.remote_addr))
     }
 
     fn remove_socket_by_id(&self, id: u64) {
@@ -126,49 +265,86 @@ impl UdpClientTransport {
 }
 
 impl Transport for UdpClientTransport {
+    type Addr = SocketAddr;
+
     fn needs_keepalive(&self) -> bool { true }
 
-    fn send(&self, mut buf: impl Buf) -> Result<()> {
-        let (sock_id, sock) = self.get_or_create_socket_for_sending()?;
-        match sock.send(&buf.copy_to_bytes(buf.remaining())) {
-            Err(e) => {
-                // connection_refused is OK (server not started)
-                if e.kind() != std::io::ErrorKind::ConnectionRefused {
-                    warn!("Udp send error: {}", e);
-                    self.remove_socket_by_id(sock_id);
-                }
-                return Err(e)?
-            },
-            _ => return Ok(())
+    fn default_addrs(&self) -> Vec<SocketAddr> { self.remote_addrs.clone() }
+
+    // the epoll fd is readable when any socket is
+    fn nonblocking_fd(&self) -> Result<Option<BorrowedFd<'_>>> {
+        self.nonblocking.store(true, Ordering::Relaxed);
+        Ok(Some(self.epoll.0.as_fd()))
+    }
+
+    fn send(&self, mut buf: impl Buf, addr: &SocketAddr) -> Result<()> {
+        let (sock_id, sock) = self.get_or_create_socket_for_sending(*addr)?;
+        let result = sock.send(&buf.copy_to_bytes(buf.remaining()));
+        self.check_send_result(sock_id, result.map(|_| ()))
+    }
+
+    fn send_batch(&self, bufs: &[(BytesMut, SocketAddr)]) -> Result<()> {
+        let mut first_error = None;
+        // a socket for each remote address
+        for remote_addr in &self.remote_addrs {
+            let bufs: Vec<&[u8]> = bufs.iter().filter(|x| x.1 == *remote_addr).map(|x| &x.0[..]).collect();
+            if bufs.is_empty() {
+                continue;
+            }
+            let result = self.get_or_create_socket_for_sending(*remote_addr).and_then(|(sock_id, sock)| {
+                let result = send_batch_to(&sock, &bufs, &vec![None; bufs.len()]);
+                self.check_send_result(sock_id, result)
+            });
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
         }
+        first_error.map_or(Ok(()), Err)
     }
 
-    fn receive(&self) -> Result<BytesMut> {
-        let mut epoll_event = EpollEvent::empty();
-        let epoll_event_size =
-            self.epoll.wait(slice::from_mut(&mut epoll_event), EpollTimeout::NONE)?;
-        assert_eq!(epoll_event_size, 1);
+    fn receive(&self) -> Result<(BytesMut, SocketAddr)> {
+        self.receive_batch(1)?.pop().ok_or(anyhow::format_err!("Nothing received"))
+    }
 
-        if epoll_event.events() != EpollFlags::EPOLLIN {
-            trace!("epoll result contains error: {:?}", epoll_event.events());
+    fn receive_batch(&self, max: usize) -> Result<Vec<(BytesMut, SocketAddr)>> {
+        let mut epoll_events = [EpollEvent::empty(); MAX_EPOLL_EVENTS];
+        let timeout = if self.nonblocking.load(Ordering::Relaxed) {
+            EpollTimeout::ZERO
+        } else {
+            EpollTimeout::try_from(POLL_INTERVAL).unwrap()
+        };
+        let num_events = self.epoll.wait(&mut epoll_events[..max.min(MAX_EPOLL_EVENTS)], timeout)?;
+        if num_events == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock))?;
         }
 
-        let sock_id = epoll_event.data();
-        let sock = self.get_socket_by_id(sock_id).unwrap();
-        let mut buf = BytesMut::zeroed(BUF_CAPACITY);
-        match sock.recv(&mut buf) {
-            Ok(buf_len) => {
-                buf.truncate(buf_len);
-                return Ok(buf)
-            },
-            Err(e) => {
-                // connection_refused is OK (server not started), keep retring
-                if e.kind() != std::io::ErrorKind::ConnectionRefused {
-                    warn!("Udp receive error: {e}");
-                    self.remove_socket_by_id(sock_id);
-                }
-                return Err(e)?
-            },
+        let mut received = Vec::new();
+        let mut first_error = None;
+        for (i, epoll_event) in epoll_events[..num_events].iter().enumerate() {
+            if epoll_event.events() != EpollFlags::EPOLLIN {
+                trace!("epoll result contains error: {:?}", epoll_event.events());
+            }
+            let sock_id = epoll_event.data();
+            let Some((sock, remote_addr)) = self.get_socket_by_id(sock_id) else {
+                continue;  // removed since
+            };
+            // leave room for a packet from each following socket
+            let max_packets = (max - received.len()).saturating_sub(num_events - i - 1).max(1);
+            match recv_batch(&sock, max_packets, true, &self.spare_bufs) {
+                Ok(packets) => received.extend(packets.into_iter().map(|(buf, _)| (buf, remote_addr))),
+                Err(e) => {
+                    // connection_refused is OK (server not started), keep retring
+                    if e.kind() != std::io::ErrorKind::ConnectionRefused {
+                        warn!("Udp receive error: {e}");
+                        self.remove_socket_by_id(sock_id);
+                    }
+                    first_error.get_or_insert(e);
+                },
+            }
+        }
+        match first_error {
+            Some(e) if received.is_empty() => Err(e.into()),
+            _ => Ok(received),
         }
     }
 }
@@ -176,8 +352,10 @@ impl Transport for UdpClientTransport {
 
 pub struct UdpServerTransport {
     sock: UdpSocket,
-    peer_addr: Mutex<Option<SocketAddr>>,
-    last_peer_addr: Mutex<Option<SocketAddr>>,
+    /// receive() doesn't wait, see Transport::nonblocking_fd.
+    /// Not with a non-blocking socket, sends should still wait for buffer space.
+    nonblocking: AtomicBool,
+    spare_bufs: SpareBuffers,
 }
 
 impl UdpServerTransport {
@@ -187,40 +365,42 @@ impl UdpServerTransport {
             .next().ok_or(anyhow::format_err!("lookup_host failed"))?;
         info!("Creating udp server transport on {local_addr}");
         let sock = UdpSocket::bind(local_addr)?;
-        Ok(UdpServerTransport {
-            sock,
-            peer_addr: Mutex::new(None),
-            last_peer_addr: Mutex::new(None),
-        })
+        sock.set_read_timeout(Some(POLL_INTERVAL))?;
+        set_buffer_sizes(&sock);
+        Ok(UdpServerTransport { sock, nonblocking: AtomicBool::new(false), spare_bufs: SpareBuffers::default() })
     }
 }
 
 impl Transport for UdpServerTransport {
+    type Addr = SocketAddr;
+
     fn needs_keepalive(&self) -> bool { false }
 
-    fn send(&self, mut buf: impl Buf) -> Result<()> {
-        let peer_addr = self.peer_addr.lock().unwrap().ok_or(
-            std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "No valid client yet"))?;
-        self.sock.send_to(&buf.copy_to_bytes(buf.remaining()), peer_addr)?;
+    fn nonblocking_fd(&self) -> Result<Option<BorrowedFd<'_>>> {
+        self.nonblocking.store(true, Ordering::Relaxed);
+        Ok(Some(self.sock.as_fd()))
+    }
+
+    fn send(&self, mut buf: impl Buf, addr: &SocketAddr) -> Result<()> {
+        self.sock.send_to(&buf.copy_to_bytes(buf.remaining()), addr)?;
         Ok(())
     }
 
-    fn receive(&self) -> Result<BytesMut> {
-        let mut buf = BytesMut::zeroed(BUF_CAPACITY);
-        let (buf_len, peer_addr) = self.sock.recv_from(&mut buf)?;
-        let _ = self.last_peer_addr.lock().unwrap().insert(peer_addr);
-        buf.truncate(buf_len);
-        Ok(buf)
+    fn send_batch(&self, bufs: &[(BytesMut, SocketAddr)]) -> Result<()> {
+        let addrs: Vec<Option<SockaddrStorage>> = bufs.iter().map(|x| Some(SockaddrStorage::from(x.1))).collect();
+        let bufs: Vec<&[u8]> = bufs.iter().map(|x| &x.0[..]).collect();
+        Ok(send_batch_to(&self.sock, &bufs, &addrs)?)
     }
 
-    fn mark_last_received_valid(&self) {
-        if let Some(peer_addr) = self.last_peer_addr.lock().unwrap().deref() {
-            let _ = self.peer_addr.lock().unwrap().insert(peer_addr.clone());
-        }
+    fn receive(&self) -> Result<(BytesMut, SocketAddr)> {
+        self.receive_batch(1)?.pop().ok_or(anyhow::format_err!("Nothing received"))
     }
 
-    fn ready_to_send(&self) -> bool {
-        self.peer_addr.lock().unwrap().is_some()
+    fn receive_batch(&self, max: usize) -> Result<Vec<(BytesMut, SocketAddr)>> {
+        let packets = recv_batch(&self.sock, max, self.nonblocking.load(Ordering::Relaxed), &self.spare_bufs)?;
+        Ok(packets.into_iter()
+           .filter_map(|(buf, addr)| Some((buf, addr?)))
+           .collect())
     }
 }
 
@@ -230,63 +410,129 @@ mod tests {
     use super::*;
     use bytes::Bytes;
 
+    #[test]
+    fn test_select_remote_addrs() {
+        let addrs: Vec<SocketAddr> = ["1.2.3.4:53", "5.6.7.8:53", "[fd00::1]:53", "[fd00::2]:53"].iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        assert_eq!(select_remote_addrs(addrs.iter().copied()), [addrs[2], addrs[0]]);
+        assert_eq!(select_remote_addrs(addrs[..2].iter().copied()), [addrs[0]]);
+        assert_eq!(select_remote_addrs(std::iter::empty()), []);
+    }
+
+    #[test]
+    fn test_ipv6() -> Result<()> {
+        let server = UdpServerTransport::create("[::1]:9997")?;
+        let client = UdpClientTransport::create("[::1]:9997", UdpClientTransportOptions::default())?;
+        let server_addr = client.default_addrs()[0];
+        client.send(&b"hello"[..], &server_addr)?;
+        let (received, client_addr) = server.receive()?;
+        assert_eq!(received, "hello");
+        server.send(&b"world"[..], &client_addr)?;
+        assert_eq!(client.receive()?, (BytesMut::from("world"), server_addr));
+        Ok(())
+    }
+
     #[test]
     fn test_basic_send_receive() -> Result<()> {
         let server = UdpServerTransport::create("127.0.0.1:9999")?;
         let client = UdpClientTransport::create("127.0.0.1:9999", UdpClientTransportOptions::default())?;
-        assert!(!server.ready_to_send());
-        assert!(client.ready_to_send());
+        let server_addr: SocketAddr = "127.0.0.1:9999".parse()?;
+        assert_eq!(server.default_addrs(), []);
+        assert_eq!(client.default_addrs(), [server_addr]);
 
         let payload = Bytes::from("hello world!");
-        client.send(payload.clone())?;
+        client.send(payload.clone(), &server_addr)?;
+
+        let (received_payload, client_addr) = server.receive()?;
+        assert_eq!(payload, received_payload);
+
+        server.send(payload.clone(), &client_addr)?;
 
         {
-            let received_payload = server.receive()?;
+            let (received_payload, addr) = client.receive()?;
             assert_eq!(payload, received_payload);
+            assert_eq!(addr, server_addr);
         }
 
-        assert!(!server.ready_to_send());
-        server.mark_last_received_valid();
-        assert!(server.ready_to_send());
+        Ok(())
+    }
 
-        server.send(payload.clone())?;
+    #[test]
+    fn test_batch() -> Result<()> {
+        let server = UdpServerTransport::create("127.0.0.1:9996")?;
+        let client = UdpClientTransport::create("127.0.0.1:9996", UdpClientTransportOptions::default())?;
+        let server_addr = client.default_addrs()[0];
+        let bufs: Vec<(BytesMut, SocketAddr)> = (0..100)
+            .map(|i| (BytesMut::from(format!("{}", i).as_str()), server_addr))
+            .collect();
+        client.send_batch(&bufs)?;
 
-        {
-            let received_payload = client.receive()?;
-            assert_eq!(payload, received_payload);
+        let mut received = Vec::new();
+        while received.len() < bufs.len() {
+            let batch = server.receive_batch(64)?;
+            assert!(!batch.is_empty() && batch.len() <= 64);
+            received.extend(batch);
         }
+        assert_eq!(received.iter().map(|x| &x.0).collect::<Vec<_>>(), bufs.iter().map(|x| &x.0).collect::<Vec<_>>());
+        let client_addr = received[0].1;
+        assert!(received.iter().all(|x| x.1 == client_addr));
+
+        server.send_batch(&received)?;
+        let mut echoed = Vec::new();
+        while echoed.len() < bufs.len() {
+            echoed.extend(client.receive_batch(64)?);
+        }
+        assert_eq!(echoed, bufs);
+        Ok(())
+    }
 
+    #[test]
+    fn test_spare_buffers() -> Result<()> {
+        let server = UdpServerTransport::create("127.0.0.1:9995")?;
+        let client = UdpClientTransport::create("127.0.0.1:9995", UdpClientTransportOptions::default())?;
+        client.send(&b"hello"[..], &client.default_addrs()[0])?;
+        assert_eq!(server.receive_batch(64)?.len(), 1);
+        // the others are kept, with their full size
+        let spare = server.spare_bufs.0.lock().unwrap().clone();
+        assert_eq!(spare.len(), 63);
+        assert!(spare.iter().all(|x| x.len() == BUF_CAPACITY));
+
+        // and all are kept if nothing is received
+        server.nonblocking.store(true, Ordering::Relaxed);
+        assert!(server.receive_batch(64).is_err());
+        assert_eq!(server.spare_bufs.0.lock().unwrap().len(), 64);
         Ok(())
     }
 
     #[test]
     fn test_multiple_request_response() -> Result<()> {
-        fn _run_server() -> Result<()> {
-            let server = UdpServerTransport::create("127.0.0.1:9998").unwrap();
-
+        fn _run_server(server: UdpServerTransport) -> Result<()> {
             loop {
-                let received = server.receive()?;
-                if received.len() == 0 {
+                let (received, addr) = server.receive()?;
+                if received.is_empty() {
                     return Ok(());
                 }
-                server.mark_last_received_valid();
-                server.send(received)?;
+                server.send(received, &addr)?;
             }
         }
+        // bind before the client starts sending, otherwise the client may get connection refused
+        let server = UdpServerTransport::create("127.0.0.1:9998")?;
         let server_thread = std::thread::spawn(|| {
-            _run_server().expect("run server error");
+            _run_server(server).expect("run server error");
         });
 
         let client = UdpClientTransport::create("127.0.0.1:9998", UdpClientTransportOptions::default())?;
+        let server_addr = client.default_addrs()[0];
         for i in 0..10000 {
             let payload_str = format!("{}", i);
             let payload = payload_str.as_bytes();
-            client.send(payload)?;
+            client.send(payload, &server_addr)?;
 
-            let received = client.receive()?;
+            let (received, _) = client.receive()?;
             assert_eq!(payload, received);
         }
-        client.send(&[] as &[u8])?;
+        client.send(&[] as &[u8], &server_addr)?;
 
         server_thread.join().unwrap();
         Ok(())
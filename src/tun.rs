@@ -1,10 +1,69 @@
-use std::{fs::File, os::fd::AsRawFd};
+use std::{fs::File, os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd}};
+use std::io::{Read, Write};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
+use bytes::BytesMut;
+use log::{debug, info, trace, warn};
+
+use nix::libc;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::unistd::{Gid, Uid};
+
+use crate::config::{IpNet, TunConfig};
+use crate::constants::{BUF_CAPACITY, ETHERNET_HEADER_SIZE, FWMARK, ROUTE_TABLE, RULE_PRIORITY};
+use crate::netlink::Netlink;
+use crate::netns::{self, NetNs};
+use crate::{mss, packet, vnet};
+
+/// Default routes currently installed with policy rules (see constants::FWMARK), of any device
+static POLICY_ROUTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether sockets of the outer transport must be marked, or their packets would loop into the tunnel
+pub fn policy_routing_active() -> bool {
+    POLICY_ROUTES.load(Ordering::Relaxed) > 0
+}
+
+#[derive(Clone)]
+pub struct TunOptions {
+    /// The device carries Ethernet frames instead of IP packets
+    pub tap: bool,
+    /// With more than one, the device is multi-queue (IFF_MULTI_QUEUE) and each queue can be read in parallel
+    pub queues: usize,
+    /// Read and write TCP super-packets with a vnet header (IFF_VNET_HDR and TSO), see vnet.rs. Not with `tap`
+    pub offload: bool,
+    /// Create and configure the device in this network namespace, see netns.rs
+    pub netns: Option<Arc<NetNs>>,
+    /// Lower the MSS of TCP SYNs read and written to fit the device MTU, see mss.rs
+    pub clamp_mss: bool,
+}
+
+impl Default for TunOptions {
+    fn default() -> Self {
+        TunOptions { tap: false, queues: 1, offload: false, netns: None, clamp_mss: false }
+    }
+}
 
 pub struct TunDevice {
-    fd: File,
+    /// A file per queue, the kernel spreads the packets it sends by flow
+    queues: Vec<File>,
     name: String,
+    index: u32,
+    tap: bool,
+    offload: bool,
+    /// With offload: a buffer per queue to read super-packets into, allocated on the first read
+    super_packets: Vec<Mutex<Vec<u8>>>,
+    /// Created here, rather than a persistent or inherited device, which is left up when dropped
+    created: bool,
+    netns: Option<Arc<NetNs>>,
+    clamp_mss: bool,
+    /// As last set, for clamp_mss (0 if unknown, then not clamped)
+    mtu: AtomicUsize,
+    /// Addresses and routes configured on the device, removed when it's dropped
+    applied: Mutex<TunConfig>,
 }
 
 
@@ -12,56 +71,438 @@ pub struct TunDevice {
 struct Ifreq {
     pub ifrn_name: [std::ffi::c_char; 16],
     pub ifru_flags: std::ffi::c_short,
+    /// Up to the size of struct ifreq, which the kernel copies
+    pub ifru_pad: [u8; 22],
 }
 
 nix::ioctl_write_int!(tun_set_iff, b'T', 202);
+nix::ioctl_write_int!(tun_set_persist, b'T', 203);
+nix::ioctl_write_int!(tun_set_owner, b'T', 204);
+nix::ioctl_write_int!(tun_set_group, b'T', 206);
+nix::ioctl_write_int!(tun_set_offload, b'T', 208);
+nix::ioctl_read_bad!(tun_get_iff, nix::request_code_read!(b'T', 210, std::mem::size_of::<std::ffi::c_uint>()), Ifreq);
 
-impl TunDevice {
-    pub fn create<S: AsRef<str>>(ifname: S) -> Result<TunDevice> {
-        let fd = std::fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open("/dev/net/tun")?;
-
-        let mut ifreq = Ifreq {
-            ifrn_name: [0; 16],
-            ifru_flags: 0x1001,  // IFF_TUN | IFF_NO_PI
+const IFF_TUN: std::ffi::c_short = 0x0001;
+const IFF_TAP: std::ffi::c_short = 0x0002;
+const IFF_MULTI_QUEUE: std::ffi::c_short = 0x0100;
+const IFF_NO_PI: std::ffi::c_short = 0x1000;
+const IFF_VNET_HDR: std::ffi::c_short = 0x4000;
+
+// TUNSETOFFLOAD flags: checksum offload, TSO for IPv4 and IPv6
+const TUN_F_CSUM: u64 = 0x01;
+const TUN_F_TSO4: u64 = 0x02;
+const TUN_F_TSO6: u64 = 0x04;
+
+/// Errors of the device file rather than of a packet written to it
+fn is_device_error(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EBADF | libc::EBADFD | libc::EIO | libc::ENODEV))
+}
+
+/// Open a queue of the device `ifname` (or a new device, if it's a pattern like tun%d), return its name
+fn open_queue(ifname: &str, flags: std::ffi::c_short) -> Result<(File, String)> {
+    let fd = std::fs::OpenOptions::new()
+        .write(true)
+        .read(true)
+        .open("/dev/net/tun")?;
+
+    let mut ifreq = Ifreq {
+        ifrn_name: [0; 16],
+        ifru_flags: flags,
+        ifru_pad: [0; 22],
+    };
+
+    let ifname_bytes = ifname.as_bytes();
+    let result_name =
+        unsafe {
+            nix::libc::memcpy(ifreq.ifrn_name.as_mut_ptr() as *mut std::ffi::c_void,
+                              ifname_bytes.as_ptr() as *const std::ffi::c_void,
+                              usize::min(15, ifname_bytes.len()));
+            tun_set_iff(fd.as_raw_fd(), &ifreq as *const Ifreq as u64)?;
+
+            std::ffi::CStr::from_ptr(ifreq.ifrn_name.as_ptr())
+                .to_string_lossy().into_owned()
         };
+    Ok((fd, result_name))
+}
 
-        let ifname_bytes = ifname.as_ref().as_bytes();
-        let result_name =
-            unsafe {
-                nix::libc::memcpy(ifreq.ifrn_name.as_mut_ptr() as *mut std::ffi::c_void,
-                                  ifname_bytes.as_ptr() as *const std::ffi::c_void,
-                                  usize::min(15, ifname_bytes.len()));
-                tun_set_iff(fd.as_raw_fd(), &ifreq as *const Ifreq as u64)?;
+fn iff_flags(options: &TunOptions) -> Result<std::ffi::c_short> {
+    if options.queues == 0 {
+        anyhow::bail!("A tun device needs at least one queue");
+    }
+    if options.offload && options.tap {
+        anyhow::bail!("Offload is not supported in TAP mode");
+    }
+    let mut flags = if options.tap { IFF_TAP } else { IFF_TUN } | IFF_NO_PI;
+    if options.queues > 1 {
+        flags |= IFF_MULTI_QUEUE;
+    }
+    if options.offload {
+        flags |= IFF_VNET_HDR;
+    }
+    Ok(flags)
+}
 
-                std::ffi::CStr::from_ptr(ifreq.ifrn_name.as_ptr())
-                    .to_string_lossy().into_owned()
+/// Create a persistent device owned by a user and/or group, which can then attach to it without privileges.
+/// Attaching needs the same options (`TunDevice::create` fails otherwise).
+pub fn create_persistent(ifname: &str, options: &TunOptions, owner: Option<Uid>, group: Option<Gid>) -> Result<String> {
+    let (fd, name) = netns::run_in(options.netns.as_deref(), || open_queue(ifname, iff_flags(options)?))?;
+    unsafe {
+        if let Some(owner) = owner {
+            tun_set_owner(fd.as_raw_fd(), owner.as_raw() as u64)?;
+        }
+        if let Some(group) = group {
+            tun_set_group(fd.as_raw_fd(), group.as_raw() as u64)?;
+        }
+        tun_set_persist(fd.as_raw_fd(), 1)?;
+    }
+    Ok(name)
+}
+
+/// Remove a persistent device, once nothing is attached to it anymore
+pub fn delete_persistent(ifname: &str, options: &TunOptions) -> Result<()> {
+    let (fd, _) = netns::run_in(options.netns.as_deref(), || open_queue(ifname, iff_flags(options)?))?;
+    unsafe { tun_set_persist(fd.as_raw_fd(), 0)?; }
+    Ok(())
+}
+
+impl TunDevice {
+    /// `ifname` is a pattern like tun%d for a new device, or the name of an existing one (e.g. persistent)
+    /// With `options.netns`, the device is created (or looked up) in that namespace.
+    pub fn create<S: AsRef<str>>(ifname: S, options: &TunOptions) -> Result<TunDevice> {
+        let flags = iff_flags(options)?;
+        let (queues, result_name, existing) = netns::run_in(options.netns.as_deref(), || {
+            let existing = nix::net::if_::if_nametoindex(ifname.as_ref()).is_ok();
+            let (fd, result_name) = open_queue(ifname.as_ref(), flags)?;
+            let mut queues = vec![fd];
+            // the other queues attach to the device just created
+            while queues.len() < options.queues {
+                queues.push(open_queue(&result_name, flags)?.0);
+            }
+            Ok((queues, result_name, existing))
+        })?;
+        TunDevice::new(queues, result_name, options, !existing)
+    }
+
+    /// Use file descriptors attached to a device by another process (one per queue), e.g. passed by a privileged parent.
+    /// With `options.netns`, the device must be in that namespace.
+    pub fn from_fds(fds: Vec<OwnedFd>, options: &TunOptions) -> Result<TunDevice> {
+        let options = TunOptions { queues: fds.len(), ..options.clone() };
+        // the device may also be persistent and multi-queue
+        let mode_flags = IFF_TUN | IFF_TAP | IFF_NO_PI | IFF_VNET_HDR;
+        let expected_flags = iff_flags(&options)? & mode_flags;
+        let mut name = None;
+        for fd in &fds {
+            let mut ifreq = Ifreq { ifrn_name: [0; 16], ifru_flags: 0, ifru_pad: [0; 22] };
+            let fd_name = unsafe {
+                tun_get_iff(fd.as_raw_fd(), &mut ifreq)?;
+                std::ffi::CStr::from_ptr(ifreq.ifrn_name.as_ptr()).to_string_lossy().into_owned()
             };
+            if ifreq.ifru_flags & mode_flags != expected_flags {
+                anyhow::bail!("Tun fd {} has flags {:#x}, expected {:#x} (see --tap and --offload)",
+                              fd.as_raw_fd(), ifreq.ifru_flags & mode_flags, expected_flags);
+            }
+            if name.as_ref().is_some_and(|x| *x != fd_name) {
+                anyhow::bail!("Tun fds are of different devices");
+            }
+            name = Some(fd_name);
+        }
+        let queues = fds.into_iter().map(File::from).collect();
+        TunDevice::new(queues, name.unwrap_or_default(), &options, false)
+    }
 
+    fn new(queues: Vec<File>, name: String, options: &TunOptions, created: bool) -> Result<TunDevice> {
+        if options.offload {
+            unsafe { tun_set_offload(queues[0].as_raw_fd(), TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6)?; }
+        }
+        let index = netns::run_in(options.netns.as_deref(), || Ok(nix::net::if_::if_nametoindex(name.as_str())?))?;
         Ok(TunDevice {
-            fd,
-            name: result_name,
+            super_packets: queues.iter().map(|_| Mutex::new(Vec::new())).collect(),
+            queues,
+            name,
+            index,
+            tap: options.tap,
+            offload: options.offload,
+            created,
+            netns: options.netns.clone(),
+            clamp_mss: options.clamp_mss,
+            mtu: AtomicUsize::new(0),
+            applied: Mutex::new(TunConfig::default()),
         })
     }
 
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// To wait for `queue` with epoll
+    pub fn queue_fd(&self, queue: usize) -> BorrowedFd<'_> {
+        self.queues[queue].as_fd()
+    }
+
+    /// Nothing to do if it's already so, e.g. a persistent device configured beforehand (then without privileges).
+    /// Not checked in another network namespace, whose devices are not in our sysfs.
+    pub fn set_mtu_and_up(&self, mtu: usize) -> Result<()> {
+        self.mtu.store(mtu, Ordering::Relaxed);
+        if self.netns.is_some() {
+            info!("Setting {} mtu {} up", self.name, mtu);
+            return self.netlink()?.set_link(self.index, Some(mtu as u32), Some(true));
+        }
+        let sysfs = |x| std::fs::read_to_string(format!("/sys/class/net/{}/{}", self.name, x)).unwrap_or_default();
+        let flags = u32::from_str_radix(sysfs("flags").trim().trim_start_matches("0x"), 16).unwrap_or(0);
+        if flags & libc::IFF_UP as u32 != 0 && sysfs("mtu").trim() == mtu.to_string() {
+            return Ok(());
+        }
+        info!("Setting {} mtu {} up", self.name, mtu);
+        self.netlink()?.set_link(self.index, Some(mtu as u32), Some(true))
+    }
+
+    /// Reads return WouldBlock instead of waiting, see read_batch
+    pub fn set_nonblocking(&self) -> Result<()> {
+        for queue in &self.queues {
+            let flags = OFlag::from_bits_retain(fcntl(queue.as_raw_fd(), FcntlArg::F_GETFL)?);
+            fcntl(queue.as_raw_fd(), FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        }
+        Ok(())
+    }
+
+    /// Wait until a packet can be read from `queue`, false on timeout
+    pub fn wait_readable(&self, queue: usize, timeout: std::time::Duration) -> Result<bool> {
+        let mut fds = [PollFd::new(self.queues[queue].as_fd(), PollFlags::POLLIN)];
+        Ok(poll(&mut fds, PollTimeout::try_from(timeout)?)? > 0)
+    }
+
+    /// Read up to `max` packets already queued in `queue` (none if the device is non-blocking and empty).
+    /// The device has no batched read syscall, but callers handle the packets together.
+    /// With offload, super-packets are split, so there may be a few more. The MSS of TCP SYNs is clamped if enabled.
+    pub fn read_batch(&self, queue: usize, max: usize) -> Result<Vec<BytesMut>> {
+        let mut bufs = Vec::new();
+        let mut super_packet = self.super_packets[queue].lock().unwrap();
+        if self.offload && super_packet.is_empty() {
+            super_packet.resize(vnet::MAX_VNET_PACKET_SIZE, 0);
+        }
+        while bufs.len() < max {
+            match self.read_packets(queue, &mut bufs, &mut super_packet) {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if bufs.is_empty() => return Err(e.into()),
+                Err(_) => break,  // reported by the next read
+            }
+        }
+        for buf in &mut bufs {
+            self.clamp_mss(buf);
+        }
+        Ok(bufs)
+    }
+
+    /// Read a packet, or the packets of a super-packet with offload
+    fn read_packets(&self, queue: usize, bufs: &mut Vec<BytesMut>, super_packet: &mut [u8]) -> std::io::Result<()> {
+        if !self.offload {
+            let mut buf = BytesMut::zeroed(BUF_CAPACITY);
+            let len = (&self.queues[queue]).read(&mut buf)?;
+            buf.truncate(len);
+            bufs.push(buf);
+            return Ok(());
+        }
+        let len = (&self.queues[queue]).read(super_packet)?;
+        match vnet::split(&super_packet[..len]) {
+            Ok(packets) => bufs.extend(packets),
+            Err(e) => debug!("Dropping packet read from {}: {}", self.name, e),
+        }
+        Ok(())
+    }
+
+    /// Queue to write a packet to. The kernel sends the packets of a flow to the queue it last received
+    /// the flow from, so the replies are read by the same reader (and not reordered with each other).
+    fn write_queue(&self, buf: &[u8]) -> usize {
+        if self.queues.len() == 1 {
+            return 0;
+        }
+        let packet = if self.tap { packet::eth_payload_ip(buf) } else { Some(buf) };
+        packet.and_then(packet::flow_hash).map_or(0, |x| (x % self.queues.len() as u64) as usize)
+    }
+
+    /// Lower the MSS of a TCP SYN, in an Ethernet frame in TAP mode
+    fn clamp_mss(&self, buf: &mut [u8]) {
+        let mtu = self.mtu.load(Ordering::Relaxed);
+        if !self.clamp_mss || mtu == 0 {
+            return;
+        }
+        let packet = match self.tap {
+            true if packet::eth_payload_ip(buf).is_none() => return,
+            true => &mut buf[ETHERNET_HEADER_SIZE..],
+            false => buf,
+        };
+        if mss::clamp(packet, mtu) {
+            trace!("Clamped TCP MSS to {}", mss::max_mss(mtu, packet[0] >> 4 == 6));
+        }
+    }
+
+    /// Write packets, each to the queue of its flow. A packet the device refuses (e.g. EINVAL for a malformed one)
+    /// is dropped, only errors of the device itself are returned (the first one).
+    /// The MSS of TCP SYNs is clamped first if enabled, and with offload TCP segments are coalesced.
+    pub fn write_batch(&self, bufs: &mut [BytesMut]) -> Result<()> {
+        for buf in bufs.iter_mut() {
+            self.clamp_mss(buf);
+        }
+        let bufs = &*bufs;
+        let coalesced;
+        let (bufs, header_size) = if self.offload {
+            coalesced = vnet::coalesce(bufs);
+            (&coalesced[..], vnet::VNET_HDR_SIZE)
+        } else {
+            (bufs, 0)
+        };
+        let mut first_error = None;
+        for buf in bufs {
+            match (&self.queues[self.write_queue(&buf[header_size..])]).write(buf) {
+                Ok(_) => (),
+                Err(e) if is_device_error(&e) => { first_error.get_or_insert(e); },
+                Err(e) => debug!("Dropping packet written to {}: {}", self.name, e),
+            }
+        }
+        first_error.map_or(Ok(()), |e| Err(e.into()))
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
-}
 
-impl std::io::Read for &TunDevice {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        (&self.fd).read(buf)
+    /// Opened in the namespace of the device, where it stays
+    fn netlink(&self) -> Result<Netlink> {
+        netns::run_in(self.netns.as_deref(), Netlink::new)
+    }
+
+    /// Apply a tun config (pushed by the server, or the server's own).
+    /// Addresses and routes of the config applied before, that are not in `config`, are removed.
+    /// Default routes are installed with policy rules, see constants::ROUTE_TABLE,
+    /// or in the main table in another network namespace (the outer traffic is not routed there).
+    pub fn apply_config(&self, config: &TunConfig) -> Result<()> {
+        let mut applied = self.applied.lock().unwrap();
+        if *applied == *config {
+            return Ok(());
+        }
+        let mut netlink = self.netlink()?;
+        if let Some(mtu) = config.mtu {
+            info!("Setting {} mtu {}", self.name, mtu);
+            netlink.set_link(self.index, Some(mtu as u32), None)?;
+            self.mtu.store(mtu as usize, Ordering::Relaxed);
+        }
+        for &route in applied.routes.iter().filter(|x| !config.routes.contains(x)) {
+            info!("Removing route {} from {}", route, self.name);
+            self.del_route(&mut netlink, route)?;
+        }
+        for &addr in applied.addresses.iter().filter(|x| !config.addresses.contains(x)) {
+            info!("Removing address {} from {}", addr, self.name);
+            netlink.del_address(self.index, addr)?;
+        }
+        // recorded as they are added, so that a failure leaves nothing behind at cleanup
+        applied.routes.retain(|x| config.routes.contains(x));
+        applied.addresses.retain(|x| config.addresses.contains(x));
+        if config.addresses.iter().any(|x| x.addr.is_ipv6()) {
+            self.enable_ipv6();
+        }
+        for &addr in &config.addresses {
+            info!("Adding address {} to {}", addr, self.name);
+            netlink.add_address(self.index, addr)?;
+            if !applied.addresses.contains(&addr) {
+                applied.addresses.push(addr);
+            }
+        }
+        for &route in &config.routes {
+            if applied.routes.contains(&route) {
+                continue;
+            }
+            info!("Adding route {} to {}", route, self.name);
+            self.add_route(&mut netlink, route)?;
+            applied.routes.push(route);
+        }
+        if !config.dns.is_empty() && applied.dns != config.dns {
+            let dns: Vec<String> = config.dns.iter().map(|x| x.to_string()).collect();
+            if let Some(netns) = &self.netns {
+                // resolved is of the original namespace, `ip netns exec` uses /etc/netns/NAME/resolv.conf instead
+                warn!("Not setting DNS servers of {} in network namespace {}: {}", self.name, netns.name(), dns.join(" "));
+            } else {
+                // only with systemd-resolved
+                info!("Setting DNS servers of {}: {}", self.name, dns.join(" "));
+                let result = Command::new("resolvectl").arg("dns").arg(&self.name).args(&dns).status();
+                if !result.is_ok_and(|x| x.success()) {
+                    warn!("Cannot set DNS servers with resolvectl");
+                }
+            }
+        }
+        applied.dns = config.dns.clone();
+        applied.mtu = config.mtu;
+        Ok(())
     }
-}
 
-impl std::io::Write for &TunDevice {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        (&self.fd).write(buf)
+    fn add_route(&self, netlink: &mut Netlink, route: IpNet) -> Result<()> {
+        if route.prefix != 0 || self.netns.is_some() {
+            return netlink.add_route(self.index, route, libc::RT_TABLE_MAIN as u32);
+        }
+        netlink.add_route(self.index, route, ROUTE_TABLE)?;
+        // before the rules: new sockets must be marked as soon as they apply
+        POLICY_ROUTES.fetch_add(1, Ordering::Relaxed);
+        let result = netlink.add_default_route_rules(route.addr.is_ipv4(), RULE_PRIORITY, ROUTE_TABLE, FWMARK);
+        if result.is_err() {
+            POLICY_ROUTES.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
     }
-    fn flush(&mut self) -> std::io::Result<()> {
-        (&self.fd).flush()
+
+    fn del_route(&self, netlink: &mut Netlink, route: IpNet) -> Result<()> {
+        if route.prefix != 0 || self.netns.is_some() {
+            return netlink.del_route(self.index, route, libc::RT_TABLE_MAIN as u32);
+        }
+        // the route goes even if the rules don't
+        let rules = netlink.del_default_route_rules(route.addr.is_ipv4(), RULE_PRIORITY, ROUTE_TABLE, FWMARK);
+        POLICY_ROUTES.fetch_sub(1, Ordering::Relaxed);
+        netlink.del_route(self.index, route, ROUTE_TABLE)?;
+        rules
+    }
+
+    /// IPv6 may be disabled on new devices (net.ipv6.conf.default.disable_ipv6)
+    fn enable_ipv6(&self) {
+        let path = format!("/proc/sys/net/ipv6/conf/{}/disable_ipv6", self.name);
+        // /proc/sys/net is of the namespace of the thread opening it
+        let result = netns::run_in(self.netns.as_deref(), || {
+            if std::fs::read_to_string(&path).is_ok_and(|x| x.trim() != "0") {
+                info!("Enabling IPv6 on {}", self.name);
+                std::fs::write(&path, "0")?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Cannot enable IPv6 on {}: {}", self.name, e);
+        }
+    }
+
+    /// Remove the addresses and routes configured, and set the device down if created here.
+    /// Best effort: failures are logged, and the rest is still cleaned up.
+    pub fn cleanup(&self) -> Result<()> {
+        let mut applied = self.applied.lock().unwrap();
+        let mut netlink = self.netlink()?;
+        for route in applied.routes.drain(..) {
+            if let Err(e) = self.del_route(&mut netlink, route) {
+                warn!("Cannot remove route {} from {}: {}", route, self.name, e);
+            }
+        }
+        for addr in applied.addresses.drain(..) {
+            if let Err(e) = netlink.del_address(self.index, addr) {
+                warn!("Cannot remove address {} from {}: {}", addr, self.name, e);
+            }
+        }
+        if !self.created {
+            return Ok(());
+        }
+        netlink.set_link(self.index, None, Some(false))
     }
 }
+
+impl Drop for TunDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.cleanup() {
+            warn!("Failed to clean up {}: {}", self.name, e);
+        }
+    }
+}
+
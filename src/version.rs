@@ -0,0 +1,90 @@
+use anyhow::Result;
+
+// Protocol versioning.
+//
+// Every encrypted packet carries a version byte inside the authenticated envelope (see cipher.rs),
+// so a peer never misinterprets a packet of another format.
+// Handshake messages always use BASE_VERSION, and carry the range of versions and the features supported by
+// the sender. The server picks the highest version both support and the common features,
+// and tells the client in the response. The session then uses that version for its data packets.
+// Since the handshake messages are hashed into the session keys, the choice cannot be tampered with.
+//
+// To change the wire format: implement it as a new version, bump MAX_VERSION, and only raise MIN_VERSION
+// once no deployed peer needs the old one. Upgraded clients and servers then work with old ones in any order.
+// Optional behaviour that doesn't change the format can be a feature bit instead.
+//
+// The framing of the transports (e.g. fakedns) is outside of this, changes there should be a new transport.
+
+/// Version of the envelope of handshake messages. Must never change, so that any peers can negotiate.
+pub const BASE_VERSION: u8 = 1;
+/// Lowest version accepted for sessions
+pub const MIN_VERSION: u8 = 1;
+/// Highest version supported, preferred for sessions
+pub const MAX_VERSION: u8 = 1;
+
+static_assertions::const_assert!(BASE_VERSION <= MIN_VERSION && MIN_VERSION <= MAX_VERSION);
+
+/// The server pushes the tun config (addresses, routes...) in the handshake response, see config.rs
+pub const FEATURE_TUN_CONFIG: u32 = 1 << 0;
+
+/// The client sends the id of its running instance in the handshake init, so that the server keeps a session
+/// for each client instance, even when several share a key (see session.rs)
+pub const FEATURE_INSTANCE_ID: u32 = 1 << 1;
+
+/// Bitmask of optional features supported by this build
+pub const FEATURES: u32 = FEATURE_TUN_CONFIG | FEATURE_INSTANCE_ID;
+
+/// Range of versions and features supported by a peer, as sent in handshake messages
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Versions {
+    pub min: u8,
+    pub max: u8,
+    pub features: u32,
+}
+
+impl Versions {
+    /// What this build supports
+    pub fn local() -> Versions {
+        Versions { min: MIN_VERSION, max: MAX_VERSION, features: FEATURES }
+    }
+
+    /// A single version, as chosen by the server
+    pub fn exact(version: u8, features: u32) -> Versions {
+        Versions { min: version, max: version, features }
+    }
+
+    /// Highest common version and common features
+    pub fn negotiate(&self, peer: &Versions) -> Result<(u8, u32)> {
+        let version = u8::min(self.max, peer.max);
+        if version < self.min || version < peer.min {
+            anyhow::bail!("No common protocol version, supported {}-{}, peer supports {}-{}",
+                          self.min, self.max, peer.min, peer.max);
+        }
+        Ok((version, self.features & peer.features))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let local = Versions { min: 2, max: 4, features: 0b0110 };
+        let peer = |min, max, features| Versions { min, max, features };
+
+        assert_eq!(local.negotiate(&peer(2, 4, 0b0110)).unwrap(), (4, 0b0110));
+        // older peer
+        assert_eq!(local.negotiate(&peer(1, 3, 0b0011)).unwrap(), (3, 0b0010));
+        // newer peer
+        assert_eq!(local.negotiate(&peer(3, 9, 0b1111)).unwrap(), (4, 0b0110));
+        // no overlap
+        assert!(local.negotiate(&peer(1, 1, 0)).is_err());
+        assert!(local.negotiate(&peer(5, 9, 0)).is_err());
+
+        // negotiation is symmetric
+        assert_eq!(peer(1, 3, 0b0011).negotiate(&local).unwrap(), (3, 0b0010));
+        assert_eq!(Versions::local().negotiate(&Versions::local()).unwrap(), (MAX_VERSION, FEATURES));
+    }
+}
@@ -0,0 +1,471 @@
+use anyhow::Result;
+use bytes::BytesMut;
+
+use crate::constants::BUF_CAPACITY;
+use crate::packet::{self, PROTO_TCP};
+
+// Virtio-net header, before each packet read from / written to a tun device with IFF_VNET_HDR.
+// With TSO enabled, the kernel hands over TCP "super-packets" of up to 64 KiB with a partial checksum,
+// instead of segmenting them to the MTU, and they're split here before being encrypted.
+// The other way, consecutive TCP segments of a flow received in a batch are coalesced before being written,
+// so that the kernel handles them as one packet (like GRO).
+//
+//   flags (1) | gso_type (1) | hdr_len (2) | gso_size (2) | csum_start (2) | csum_offset (2), in host byte order
+
+pub const VNET_HDR_SIZE: usize = 10;
+/// Max size of a super-packet with its vnet header
+pub const MAX_VNET_PACKET_SIZE: usize = VNET_HDR_SIZE + 65535;
+
+const F_NEEDS_CSUM: u8 = 1;
+const GSO_NONE: u8 = 0;
+const GSO_TCPV4: u8 = 1;
+const GSO_TCPV6: u8 = 4;
+const GSO_ECN: u8 = 0x80;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_FLAG_CWR: u8 = 0x80;
+const TCP_CHECKSUM_OFFSET: usize = 16;
+const UDP_CHECKSUM_OFFSET: usize = 6;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+struct VnetHeader {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHeader {
+    fn parse(buf: &[u8]) -> Option<VnetHeader> {
+        let b = buf.get(..VNET_HDR_SIZE)?;
+        let u16_at = |i: usize| u16::from_ne_bytes([b[i], b[i + 1]]);
+        Some(VnetHeader {
+            flags: b[0],
+            gso_type: b[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; VNET_HDR_SIZE] {
+        let mut b = [0u8; VNET_HDR_SIZE];
+        b[0] = self.flags;
+        b[1] = self.gso_type;
+        for (i, x) in [self.hdr_len, self.gso_size, self.csum_start, self.csum_offset].into_iter().enumerate() {
+            b[2 + i * 2..4 + i * 2].copy_from_slice(&x.to_ne_bytes());
+        }
+        b
+    }
+}
+
+/// One's complement sum of the big endian 16-bit words of `data`, added to `acc`, to be folded
+fn sum(data: &[u8], mut acc: u64) -> u64 {
+    let mut words = data.chunks_exact(4);
+    for x in &mut words {
+        acc += u32::from_be_bytes([x[0], x[1], x[2], x[3]]) as u64;
+    }
+    let mut pairs = words.remainder().chunks_exact(2);
+    for x in &mut pairs {
+        acc += u16::from_be_bytes([x[0], x[1]]) as u64;
+    }
+    if let [x] = pairs.remainder() {
+        acc += (*x as u64) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u64) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+fn is_ipv4(packet: &[u8]) -> bool {
+    packet[0] >> 4 == 4
+}
+
+/// Sum of the pseudo header of a TCP packet, with `len` bytes of TCP header and payload
+fn pseudo_header_sum(packet: &[u8], len: usize) -> u64 {
+    let addrs = if is_ipv4(packet) { &packet[12..20] } else { &packet[8..40] };
+    sum(addrs, PROTO_TCP as u64 + len as u64)
+}
+
+/// Set the length field(s) of an IP packet to its size
+fn set_ip_length(packet: &mut [u8]) {
+    let len = packet.len() as u16;
+    if is_ipv4(packet) {
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        let header_len = (packet[0] & 0x0f) as usize * 4;
+        packet[10..12].fill(0);
+        let checksum = !fold(sum(&packet[..header_len], 0));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    } else {
+        packet[4..6].copy_from_slice(&(len - 40).to_be_bytes());
+    }
+}
+
+fn set_tcp_checksum(packet: &mut [u8], offset: usize) {
+    let at = offset + TCP_CHECKSUM_OFFSET;
+    packet[at..at + 2].fill(0);
+    let checksum = !fold(sum(&packet[offset..], pseudo_header_sum(packet, packet.len() - offset)));
+    packet[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+fn packet_buf(parts: &[&[u8]]) -> BytesMut {
+    let len = parts.iter().map(|x| x.len()).sum();
+    let mut buf = BytesMut::with_capacity(usize::max(BUF_CAPACITY, len));
+    for part in parts {
+        buf.extend_from_slice(part);
+    }
+    buf
+}
+
+/// Split a packet read from the tun device (with its vnet header) into packets of at most the MTU,
+/// with complete checksums
+pub fn split(buf: &[u8]) -> Result<Vec<BytesMut>> {
+    let Some(header) = VnetHeader::parse(buf) else {
+        anyhow::bail!("Truncated vnet header");
+    };
+    let packet = &buf[VNET_HDR_SIZE..];
+    match header.gso_type & !GSO_ECN {
+        GSO_NONE => {
+            let mut packet = packet_buf(&[packet]);
+            if header.flags & F_NEEDS_CSUM != 0 {
+                complete_checksum(&mut packet, header.csum_start as usize, header.csum_offset as usize)?;
+            }
+            Ok(vec![packet])
+        },
+        GSO_TCPV4 | GSO_TCPV6 => split_tcp(packet, header.gso_size as usize),
+        x => anyhow::bail!("Unsupported GSO type {}", x),
+    }
+}
+
+/// The checksum field holds the sum of the pseudo header, add the rest from `start`
+fn complete_checksum(packet: &mut [u8], start: usize, offset: usize) -> Result<()> {
+    let at = start + offset;
+    if at + 2 > packet.len() {
+        anyhow::bail!("Invalid checksum offset");
+    }
+    let mut checksum = !fold(sum(&packet[start..], 0));
+    if checksum == 0 && offset == UDP_CHECKSUM_OFFSET {
+        checksum = 0xffff;  // 0 is no checksum for UDP
+    }
+    packet[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    Ok(())
+}
+
+fn split_tcp(packet: &[u8], gso_size: usize) -> Result<Vec<BytesMut>> {
+    let Some((PROTO_TCP, ip_len)) = packet::transport_header(packet) else {
+        anyhow::bail!("TCP segmentation of a packet which is not TCP");
+    };
+    let tcp_len = packet.get(ip_len + 12).map_or(0, |x| (x >> 4) as usize * 4);
+    let header_len = ip_len + tcp_len;
+    if tcp_len < 20 || header_len > packet.len() || gso_size == 0 {
+        anyhow::bail!("Invalid TCP super-packet");
+    }
+    let (header, payload) = packet.split_at(header_len);
+    let id = u16::from_be_bytes([packet[4], packet[5]]);
+    let seq = u32::from_be_bytes(packet[ip_len + 4..ip_len + 8].try_into()?);
+    let flags = packet[ip_len + 13];
+
+    let num_segments = payload.len().div_ceil(gso_size).max(1);
+    let mut segments = Vec::with_capacity(num_segments);
+    for i in 0..num_segments {
+        let chunk = &payload[usize::min(i * gso_size, payload.len())..usize::min((i + 1) * gso_size, payload.len())];
+        let mut segment = packet_buf(&[header, chunk]);
+        if is_ipv4(&segment) {
+            segment[4..6].copy_from_slice(&id.wrapping_add(i as u16).to_be_bytes());
+        }
+        set_ip_length(&mut segment);
+        let tcp = &mut segment[ip_len..];
+        tcp[4..8].copy_from_slice(&seq.wrapping_add((i * gso_size) as u32).to_be_bytes());
+        // FIN and PSH on the last segment only, CWR on the first only
+        let mut segment_flags = flags;
+        if i + 1 < num_segments {
+            segment_flags &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+        }
+        if i > 0 {
+            segment_flags &= !TCP_FLAG_CWR;
+        }
+        tcp[13] = segment_flags;
+        set_tcp_checksum(&mut segment, ip_len);
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// A TCP segment which may be coalesced: no IP options or extension headers, no flags but ACK (and PSH),
+/// and a valid checksum (the kernel doesn't check it again after coalescing)
+struct Segment {
+    ip_len: usize,
+    header_len: usize,
+    seq: u32,
+    flags: u8,
+}
+
+fn parse_segment(packet: &[u8]) -> Option<Segment> {
+    let be16 = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]) as usize;
+    let ip_len = match packet.first()? >> 4 {
+        4 if packet.len() >= 20 && packet[0] == 0x45 && packet[9] == PROTO_TCP
+            && be16(6) & 0x3fff == 0 && be16(2) == packet.len() => 20,
+        6 if packet.len() >= 40 && packet[6] == PROTO_TCP && be16(4) + 40 == packet.len() => 40,
+        _ => return None,
+    };
+    let tcp_len = (*packet.get(ip_len + 12)? >> 4) as usize * 4;
+    let header_len = ip_len + tcp_len;
+    if tcp_len < 20 || header_len > packet.len() {
+        return None;
+    }
+    let flags = packet[ip_len + 13];
+    if flags & !TCP_FLAG_PSH != TCP_FLAG_ACK
+        || fold(sum(&packet[ip_len..], pseudo_header_sum(packet, packet.len() - ip_len))) != 0xffff {
+        return None;
+    }
+    Some(Segment {
+        ip_len,
+        header_len,
+        seq: u32::from_be_bytes(packet[ip_len + 4..ip_len + 8].try_into().ok()?),
+        flags,
+    })
+}
+
+/// Whether two segments have the same headers, except for the fields which vary along a stream:
+/// IP length, id and checksum, TCP sequence number, flags and checksum
+fn same_headers(a: &[u8], b: &[u8], ip_len: usize, header_len: usize) -> bool {
+    let ip = if ip_len == 20 {
+        a[..2] == b[..2] && a[6..10] == b[6..10] && a[12..20] == b[12..20]
+    } else {
+        a[..4] == b[..4] && a[6..40] == b[6..40]
+    };
+    let tcp = ip_len;
+    ip && a[tcp..tcp + 4] == b[tcp..tcp + 4]
+        && a[tcp + 8..tcp + 13] == b[tcp + 8..tcp + 13]
+        && a[tcp + 14..tcp + 16] == b[tcp + 14..tcp + 16]
+        && a[tcp + 18..header_len] == b[tcp + 18..header_len]
+}
+
+/// A packet to write, coalesced from the segments of a flow
+struct Group {
+    /// vnet header and packet
+    buf: BytesMut,
+    segment: Option<Segment>,
+    count: usize,
+    gso_size: usize,
+    next_seq: u32,
+    last_len: usize,
+    last_flags: u8,
+}
+
+impl Group {
+    fn new(packet: &[u8], segment: Option<Segment>) -> Group {
+        let mut buf = BytesMut::with_capacity(VNET_HDR_SIZE + packet.len());
+        buf.extend_from_slice(&VnetHeader::default().to_bytes());
+        buf.extend_from_slice(packet);
+        let (gso_size, next_seq, last_flags) = match &segment {
+            Some(x) => {
+                let len = packet.len() - x.header_len;
+                (len, x.seq.wrapping_add(len as u32), x.flags)
+            },
+            None => (0, 0, 0),
+        };
+        Group { buf, segment, count: 1, gso_size, next_seq, last_len: gso_size, last_flags }
+    }
+
+    fn packet(&self) -> &[u8] {
+        &self.buf[VNET_HDR_SIZE..]
+    }
+
+    /// Whether `packet` is the same flow, its headers can be merged
+    fn matches(&self, packet: &[u8], segment: &Segment) -> bool {
+        self.segment.as_ref().is_some_and(|x| x.ip_len == segment.ip_len && x.header_len == segment.header_len
+                                          && same_headers(self.packet(), packet, x.ip_len, x.header_len))
+    }
+
+    /// Segments have the same size but the last one, which may be smaller, and only the last one may have PSH
+    fn try_append(&mut self, packet: &[u8], segment: &Segment) -> bool {
+        let len = packet.len() - segment.header_len;
+        if self.last_flags != TCP_FLAG_ACK || self.last_len != self.gso_size || segment.seq != self.next_seq
+            || len == 0 || len > self.gso_size || self.packet().len() + len > 65535 {
+            return false;
+        }
+        self.buf.extend_from_slice(&packet[segment.header_len..]);
+        self.count += 1;
+        self.next_seq = self.next_seq.wrapping_add(len as u32);
+        self.last_len = len;
+        self.last_flags = segment.flags;
+        true
+    }
+
+    fn finish(mut self) -> BytesMut {
+        let Some(segment) = self.segment.filter(|_| self.count > 1) else {
+            return self.buf;
+        };
+        let ip_len = segment.ip_len;
+        let header = VnetHeader {
+            flags: F_NEEDS_CSUM,
+            gso_type: if ip_len == 20 { GSO_TCPV4 } else { GSO_TCPV6 },
+            hdr_len: segment.header_len as u16,
+            gso_size: self.gso_size as u16,
+            csum_start: ip_len as u16,
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+        };
+        self.buf[..VNET_HDR_SIZE].copy_from_slice(&header.to_bytes());
+        let packet = &mut self.buf[VNET_HDR_SIZE..];
+        set_ip_length(packet);
+        packet[ip_len + 13] = self.last_flags;
+        // a partial checksum, of the pseudo header only
+        let partial = fold(pseudo_header_sum(packet, packet.len() - ip_len));
+        packet[ip_len + TCP_CHECKSUM_OFFSET..ip_len + TCP_CHECKSUM_OFFSET + 2].copy_from_slice(&partial.to_be_bytes());
+        self.buf
+    }
+}
+
+/// Coalesce consecutive TCP segments of the same flows, return the packets to write to the tun device,
+/// with their vnet header. Other packets are left as they are.
+pub fn coalesce(packets: &[BytesMut]) -> Vec<BytesMut> {
+    let mut groups: Vec<Group> = Vec::new();
+    for packet in packets {
+        let segment = parse_segment(packet);
+        if let Some(segment) = &segment {
+            // the latest group of the flow, the next segment can only follow its last one
+            if let Some(group) = groups.iter_mut().rev().find(|x| x.matches(packet, segment)) {
+                if group.try_append(packet, segment) {
+                    continue;
+                }
+            }
+        }
+        groups.push(Group::new(packet, segment));
+    }
+    groups.into_iter().map(Group::finish).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_packet(ipv6: bool, seq: u32, flags: u8, payload: &[u8]) -> BytesMut {
+        let ip_len = if ipv6 { 40 } else { 20 };
+        let mut packet = BytesMut::zeroed(ip_len + 20 + payload.len());
+        if ipv6 {
+            packet[0] = 0x60;
+            packet[6] = PROTO_TCP;
+            packet[7] = 64;
+            packet[23] = 2;
+            packet[39] = 1;
+        } else {
+            packet[0] = 0x45;
+            packet[6] = 0x40;  // DF
+            packet[8] = 64;
+            packet[9] = PROTO_TCP;
+            packet[12..16].copy_from_slice(&[10, 9, 0, 2]);
+            packet[16..20].copy_from_slice(&[10, 9, 0, 1]);
+        }
+        let tcp = &mut packet[ip_len..];
+        tcp[0..4].copy_from_slice(&[0xc0, 0x00, 0x00, 0x16]);
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+        tcp[8..12].copy_from_slice(&7u32.to_be_bytes());
+        tcp[12] = 0x50;
+        tcp[13] = flags;
+        tcp[14..16].copy_from_slice(&[0x01, 0x00]);
+        tcp[20..].copy_from_slice(payload);
+        set_ip_length(&mut packet);
+        set_tcp_checksum(&mut packet, ip_len);
+        packet
+    }
+
+    fn is_valid(packet: &[u8]) -> bool {
+        let ip_len = if is_ipv4(packet) { 20 } else { 40 };
+        fold(sum(&packet[ip_len..], pseudo_header_sum(packet, packet.len() - ip_len))) == 0xffff
+    }
+
+    #[test]
+    fn test_header() {
+        let header = VnetHeader { flags: 1, gso_type: 4, hdr_len: 60, gso_size: 1000, csum_start: 40, csum_offset: 16 };
+        assert_eq!(VnetHeader::parse(&header.to_bytes()), Some(header));
+        assert_eq!(VnetHeader::parse(&[0; 9]), None);
+    }
+
+    #[test]
+    fn test_split() {
+        for ipv6 in [false, true] {
+            let ip_len = if ipv6 { 40 } else { 20 };
+            let payload: Vec<u8> = (0..2500u32).map(|x| x as u8).collect();
+            let mut packet = tcp_packet(ipv6, 100, TCP_FLAG_ACK | TCP_FLAG_PSH, &payload);
+            // as handed over by the kernel: a partial checksum
+            let partial = fold(pseudo_header_sum(&packet, packet.len() - ip_len));
+            packet[ip_len + 16..ip_len + 18].copy_from_slice(&partial.to_be_bytes());
+            let header = VnetHeader {
+                flags: F_NEEDS_CSUM,
+                gso_type: if ipv6 { GSO_TCPV6 } else { GSO_TCPV4 },
+                hdr_len: (ip_len + 20) as u16,
+                gso_size: 1000,
+                csum_start: ip_len as u16,
+                csum_offset: 16,
+            };
+            let mut buf = header.to_bytes().to_vec();
+            buf.extend_from_slice(&packet);
+
+            let segments = split(&buf).unwrap();
+            assert_eq!(segments.len(), 3);
+            assert_eq!(segments[2].len(), ip_len + 20 + 500);
+            let mut expected = tcp_packet(ipv6, 1100, TCP_FLAG_ACK, &payload[1000..2000]);
+            if !ipv6 {
+                expected[5] = 1;  // incremented id
+                set_ip_length(&mut expected);
+            }
+            assert_eq!(segments[1], expected);
+            assert_eq!(segments[2][ip_len + 13], TCP_FLAG_ACK | TCP_FLAG_PSH);
+            assert!(segments.iter().all(|x| is_valid(x)));
+
+            // coalesced back
+            let coalesced = coalesce(&segments);
+            assert_eq!(coalesced.len(), 1);
+            let header = VnetHeader::parse(&coalesced[0]).unwrap();
+            assert_eq!((header.gso_size, header.hdr_len), (1000, (ip_len + 20) as u16));
+            let mut packet = split(&coalesced[0]).unwrap();
+            assert_eq!(packet.len(), 3);
+            assert_eq!(packet.pop(), segments.last().cloned());
+        }
+
+        let mut buf = VnetHeader::default().to_bytes().to_vec();
+        buf.extend_from_slice(&tcp_packet(false, 1, TCP_FLAG_ACK, b"x"));
+        assert_eq!(split(&buf).unwrap(), vec![BytesMut::from(&buf[VNET_HDR_SIZE..])]);
+        assert!(split(&buf[..5]).is_err());
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let a = tcp_packet(false, 0, TCP_FLAG_ACK, &[1; 100]);
+        let b = tcp_packet(false, 100, TCP_FLAG_ACK, &[2; 100]);
+        let c = tcp_packet(false, 200, TCP_FLAG_ACK | TCP_FLAG_PSH, &[3; 50]);
+        let d = tcp_packet(false, 250, TCP_FLAG_ACK, &[4; 100]);
+        let v6 = tcp_packet(true, 0, TCP_FLAG_ACK, &[5; 100]);
+        let mut bad = tcp_packet(false, 100, TCP_FLAG_ACK, &[2; 100]);
+        bad[30] ^= 1;
+
+        // another flow in between, ends after PSH
+        let out = coalesce(&[a.clone(), v6.clone(), b.clone(), c.clone(), d.clone()]);
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[0].len(), VNET_HDR_SIZE + 20 + 20 + 250);
+        assert_eq!(out[0][1], GSO_TCPV4);
+        assert_eq!(out[0][VNET_HDR_SIZE + 20 + 13], TCP_FLAG_ACK | TCP_FLAG_PSH);
+        assert_eq!(&out[1][VNET_HDR_SIZE..], &v6[..]);
+        assert_eq!(&out[2][..VNET_HDR_SIZE], &[0; VNET_HDR_SIZE]);
+        assert_eq!(&out[2][VNET_HDR_SIZE..], &d[..]);
+
+        // not in sequence, bad checksum, or a smaller segment before
+        assert_eq!(coalesce(&[a.clone(), c.clone()]).len(), 2);
+        assert_eq!(coalesce(&[a.clone(), bad]).len(), 2);
+        let short = tcp_packet(false, 100, TCP_FLAG_ACK, &[2; 50]);
+        assert_eq!(coalesce(&[a.clone(), short, tcp_packet(false, 150, TCP_FLAG_ACK, &[2; 100])]).len(), 2);
+        assert_eq!(coalesce(&[BytesMut::from(&[0x45u8; 3][..])]).len(), 1);
+    }
+}